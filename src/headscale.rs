@@ -1,22 +1,28 @@
-use reqwest;
+use chrono::{DateTime, FixedOffset};
 use reqwest::{Url,header};
-use clap::Parser;
+use clap::Args;
 
 use serde::Deserialize;
 use serde_json::from_str;
 //use serde_aux::field_attributes::deserialize_number_from_string;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
+use log::{debug, info};
+use crate::{logging, secret};
 
-#[derive(Parser)]
-struct HeadscaleClientDetails {
+#[derive(Args)]
+pub struct HeadscaleClientDetails {
     // https://github.com/juanfont/headscale/blob/109989005d414240bbe730ae1d8688dfe90d7e34/config-example.yaml#L33
-    #[arg(long = "headscale_domain", alias = "hs_d", env = "HEADSCALE_DOMAIN",
+    #[arg(id = "headscale_domain", long = "headscale_domain", alias = "hs_d", env = "HEADSCALE_DOMAIN",
         help = "Domain of Headscale server", default_value = "https://localhost:50433")]
     host: String,
     #[arg(long = "headscale_auth", alias = "hs_a", env = "HEADSCALE_AUTH",
         help = "Headscale API key")]
-    auth: String,
+    auth: Option<String>,
+    #[arg(long = "headscale_auth_file", alias = "hs_af", env = "HEADSCALE_AUTH_FILE",
+        help = r#"File containing the Headscale API key (used if HEADSCALE_AUTH isn't set,
+falls back to the "headscale_auth" systemd credential)"#)]
+    auth_file: Option<String>,
 
     #[arg(long = "headscale_tld", alias = "hs_t", env = "HEADSCALE_TLD",
         help = r#"Headscale server's magicDNS's root level TLD (eg. something."tailscale")"#,
@@ -27,8 +33,14 @@ struct HeadscaleClientDetails {
 impl HeadscaleClientDetails {
     // We will skip validation in the config parser stage as we cannot do that
     // without setting up the client first
-    fn new() -> Result<HeadscaleClientDetails> {
-        Ok(HeadscaleClientDetails::parse())
+    pub fn resolve_secrets(&mut self, allow_world_readable: bool) -> Result<()> {
+        self.auth = secret::resolve(self.auth.as_deref(), self.auth_file.as_deref(),
+            "headscale_auth", allow_world_readable)?;
+        if self.auth.is_none() {
+            bail!("No Headscale API key has been specified (HEADSCALE_AUTH or HEADSCALE_AUTH_FILE)");
+        }
+
+        Ok(())
     }
 }
 
@@ -50,7 +62,10 @@ impl HeadscaleClient {
     fn from(details: HeadscaleClientDetails) -> Result<HeadscaleClient> {
         let mut headers = reqwest::header::HeaderMap::new();
 
-        let mut auth_value = header::HeaderValue::from_str(&format!("Bearer {}", &details.auth))?;
+        let auth = details.auth.as_deref().unwrap_or_default();
+        logging::add_secret(auth);
+
+        let mut auth_value = header::HeaderValue::from_str(&format!("Bearer {}", auth))?;
         auth_value.set_sensitive(true);
         headers.insert(reqwest::header::AUTHORIZATION, auth_value);
 
@@ -76,8 +91,8 @@ impl HeadscaleClient {
         Ok(())
     }
 
-    pub fn new() -> Result<HeadscaleClient> {
-        let client = HeadscaleClient::from(HeadscaleClientDetails::new()?)?;
+    pub fn new(details: HeadscaleClientDetails) -> Result<HeadscaleClient> {
+        let client = HeadscaleClient::from(details)?;
 
         client.validate()?;
 
@@ -88,6 +103,7 @@ impl HeadscaleClient {
         let url = Url::parse(
             &(self.base_url.to_string() + "/api/v1/user"))?;
 
+        debug!(url = url.as_str(); "Querying Headscale users");
         let res = self.client.get(url).send()?.error_for_status()?;
 
         // Headscale API dumb asf so this had to do
@@ -96,7 +112,10 @@ impl HeadscaleClient {
             users: Vec<HeadscaleUser>,
         }
 
-        Ok(from_str::<UserResponse>(&res.text()?)?.users)
+        let users = from_str::<UserResponse>(&res.text()?)?.users;
+        info!(users = users.len(); "Received Headscale users");
+
+        Ok(users)
     }
     pub fn get_node_list_with_addresses(&self) -> Result<Vec<HeadscaleNode>> {
         let urls = vec![Url::parse(&(self.base_url.to_string() + "/api/v1/node"))?];
@@ -105,16 +124,15 @@ impl HeadscaleClient {
             nodes: Vec<HeadscaleNode>
         }
 
-        let nodes: Vec<HeadscaleNode> = urls.into_iter().map(
-            |url| {
-                let res = self.client.get(url).send()
-                    .unwrap()
-                    .error_for_status()
-                    .unwrap();
+        let mut nodes: Vec<HeadscaleNode> = Vec::new();
+        for url in urls {
+            debug!(url = url.as_str(); "Querying Headscale nodes");
+            let res = self.client.get(url).send()?.error_for_status()?;
 
-                from_str::<NodeResponse>(&res.text().unwrap()).unwrap().nodes
-            }
-        ).collect::<Vec<_>>().concat();
+            nodes.append(&mut from_str::<NodeResponse>(&res.text()?)?.nodes);
+        }
+
+        info!(nodes = nodes.len(); "Received Headscale nodes");
 
         Ok(nodes)
     }
@@ -143,13 +161,70 @@ pub struct HeadscaleNode {
     pub given_name:   String,        // magicDNS machine name
     pub user:         HeadscaleUser,
     pub online:       bool,
+    // older Headscale versions split up the tags, newer ones only have the one list
+    #[serde(default)]
+    pub forced_tags:  Vec<String>,
+    #[serde(default)]
+    pub valid_tags:   Vec<String>,
+    #[serde(default)]
+    pub tags:         Vec<String>,
+    // RFC 3339, see get_last_seen
+    #[serde(default)]
+    pub last_seen:    Option<String>,
+    // Not from Headscale at all, see external
+    #[serde(skip)]
+    pub external:     bool,
 }
 
 impl HeadscaleNode {
+    // Stands in for a Traefik endpoint outside of the tailnet, so that it can go
+    // through the same pipeline as the discovered nodes
+    pub fn external(name: &str, addresses: &[String]) -> Self {
+        HeadscaleNode {
+            ip_addresses: addresses.to_vec(),
+            given_name:   name.to_string(),
+            user:         HeadscaleUser { name: String::new() },
+            online:       true,
+            forced_tags:  Vec::new(),
+            valid_tags:   Vec::new(),
+            tags:         Vec::new(),
+            last_seen:    None,
+            external:     true,
+        }
+    }
+
+    // Every ACL tag of the node (eg. "tag:server"), regardless of where it came from
+    pub fn get_tags(&self) -> Vec<&str> {
+        let mut tags: Vec<&str> = Vec::new();
+        for i in self.forced_tags.iter().chain(&self.valid_tags).chain(&self.tags) {
+            if !tags.contains(&i.as_str()) { tags.push(i.as_str()); }
+        }
+        tags
+    }
+
+    pub fn get_last_seen(&self) -> Option<DateTime<FixedOffset>> {
+        DateTime::parse_from_rfc3339(self.last_seen.as_deref()?).ok()
+    }
+
     pub fn get_magic_dns_domains(&self, client: &HeadscaleClient) -> Vec<String> {
         client.get_magic_tld().into_iter()
             .map(|x| 
                 self.given_name.clone() + "." + self.user.name.as_str() + "." + x.as_str())
             .collect()
     }
+
+    // The current (`node.base_domain`) magicDNS name, if it's something the tailnet can resolve
+    pub fn get_magic_dns_fqdn(&self, client: &HeadscaleClient) -> Option<String> {
+        if self.external { return None; }
+
+        let tld = client.get_magic_tld().into_iter().next()?;
+
+        let valid_label = !self.given_name.is_empty() && self.given_name.len() <= 63 &&
+            self.given_name.chars().all(|x| x.is_ascii_alphanumeric() || x == '-') &&
+            !self.given_name.starts_with('-') && !self.given_name.ends_with('-');
+
+        if !valid_label { return None; }
+
+        Some(self.given_name.to_lowercase() + "." + tld.as_str())
+    }
 }
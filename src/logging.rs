@@ -0,0 +1,174 @@
+use std::io::Write;
+use std::sync::{OnceLock, RwLock};
+use clap::{Args, ValueEnum};
+use log::{kv, LevelFilter, Log, Metadata, Record};
+use regex::Regex;
+use serde_json::{json, Map, Value};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    Text,
+    // one JSON object per line, for journald/Loki and friends
+    Json,
+}
+
+#[derive(Args)]
+pub struct LoggingSettings {
+    #[arg(short = 'v', action = clap::ArgAction::Count,
+        help = "Log more (-v for info, -vv for debug, -vvv for trace)")]
+    verbose: u8,
+
+    #[arg(short = 'q', action = clap::ArgAction::Count,
+        help = "Log less (-q for errors only, -qq for nothing at all)")]
+    quiet: u8,
+
+    #[arg(long = "log_level", alias = "ll", env = "LOG_LEVEL",
+        help = "Log level (off, error, warn, info, debug, trace), overrides -v and -q")]
+    level: Option<LevelFilter>,
+
+    #[arg(long = "log_format", alias = "lf", env = "LOG_FORMAT",
+        help = "Format of the log lines written to stderr", value_enum, default_value_t = LogFormat::Text)]
+    format: LogFormat,
+}
+
+impl LoggingSettings {
+    fn level(&self) -> LevelFilter {
+        if let Some(level) = self.level {
+            return level;
+        }
+
+        // warnings and errors by default, so that a timer unit stays quiet unless something's wrong
+        match self.verbose as i16 - self.quiet as i16 {
+            i16::MIN..=-2 => LevelFilter::Off,
+            -1 => LevelFilter::Error,
+            0  => LevelFilter::Warn,
+            1  => LevelFilter::Info,
+            2  => LevelFilter::Debug,
+            _  => LevelFilter::Trace,
+        }
+    }
+
+    pub fn init(&self) {
+        let logger = LOGGER.get_or_init(|| Logger {
+            format: self.format,
+            patterns: Regex::new(AUTHORIZATION).unwrap(),
+        });
+
+        // only fails if it's already been set, which is fine
+        if log::set_logger(logger).is_ok() {
+            log::set_max_level(self.level());
+        }
+    }
+}
+
+pub fn is_initialized() -> bool {
+    LOGGER.get().is_some()
+}
+
+// Makes sure the given value never shows up in the logs
+pub fn add_secret(secret: &str) {
+    if secret.is_empty() { return; }
+
+    let mut secrets = SECRETS.write().unwrap();
+    if !secrets.iter().any(|x| x == secret) {
+        secrets.push(secret.to_string());
+    }
+}
+
+// Credentials that make it into error messages some other way than through add_secret,
+// eg. `Authorization: Basic dXNlcjpwYXNz` or `"authorization": "Bearer ..."`
+const AUTHORIZATION: &str = r#"(?i)(\bauthorization"?\s*[:=]\s*"?(?:bearer|basic))\s+[A-Za-z0-9+/=._~-]+"#;
+
+static SECRETS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+struct Logger {
+    format: LogFormat,
+    patterns: Regex,
+}
+
+impl Logger {
+    fn redact(&self, text: &str) -> String {
+        let mut text = self.patterns.replace_all(text, "$1 [REDACTED]").into_owned();
+        for secret in SECRETS.read().unwrap().iter() {
+            text = text.replace(secret.as_str(), "[REDACTED]");
+        }
+        text
+    }
+}
+
+struct Fields(Vec<(String, String)>);
+
+impl<'kvs> kv::VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // the libraries we use are rather chatty, so they only get to talk at trace level
+        let ours = metadata.target().starts_with(env!("CARGO_CRATE_NAME"));
+        let max_level = if ours || log::max_level() == LevelFilter::Trace {
+            log::max_level()
+        } else {
+            log::max_level().min(LevelFilter::Warn)
+        };
+
+        metadata.level() <= max_level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) { return; }
+
+        let mut fields = Fields(Vec::new());
+        let _ = record.key_values().visit(&mut fields);
+
+        let timestamp = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+        let message = self.redact(&record.args().to_string());
+
+        let line = match self.format {
+            LogFormat::Text => {
+                let mut line = format!("{} {:<5} {}: {}", timestamp, record.level(), record.target(), message);
+                for (key, value) in &fields.0 {
+                    line += &format!(" {}={:?}", key, self.redact(value));
+                }
+                line
+            },
+            LogFormat::Json => {
+                let mut object = Map::new();
+                object.insert("timestamp".to_string(), json!(timestamp));
+                object.insert("level".to_string(), json!(record.level().as_str().to_lowercase()));
+                object.insert("target".to_string(), json!(record.target()));
+                object.insert("message".to_string(), json!(message));
+                for (key, value) in fields.0 {
+                    object.insert(key, json!(self.redact(&value)));
+                }
+                Value::Object(object).to_string()
+            },
+        };
+
+        let _ = writeln!(std::io::stderr().lock(), "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stderr().flush();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_authorization_headers() {
+        let logger = Logger { format: LogFormat::Text, patterns: Regex::new(AUTHORIZATION).unwrap() };
+
+        assert_eq!(logger.redact("Authorization: Basic dXNlcjpwYXNz"), "Authorization: Basic [REDACTED]");
+        assert_eq!(logger.redact(r#"{"authorization": "Bearer abc.def"}"#), r#"{"authorization": "Bearer [REDACTED]"}"#);
+        // the words on their own are just words
+        assert_eq!(logger.redact("basic auth failed for bearer of bad news"), "basic auth failed for bearer of bad news");
+    }
+}
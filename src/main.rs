@@ -1,13 +1,73 @@
 mod headscale;
+mod logging;
+mod metrics;
 mod traefik;
+mod output;
 mod processing;
+mod rule;
+mod secret;
+mod settings;
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+use std::error::Error;
+use std::process::ExitCode;
+use std::thread::sleep;
+use log::{error, info};
+
+// The whole chain of an error, as the top level message alone is usually too vague
+fn describe(e: &dyn Error) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(x) = source {
+        message += &format!(": {}", x);
+        source = x.source();
+    }
+    message
+}
+
+// What --dry-run exits with when the records would change. It's distinct from the exit code
+// of a failure (1) and of a usage error (2, from clap) so that scripts can tell them apart.
+const DRY_RUN_CHANGES: u8 = 3;
+
+fn run() -> Result<ExitCode, Box<dyn Error>> {
     let mut state = processing::Processing::new()?;
 
-    state.update_servers()?;
-    state.update_routers()?;
-    state.generate_json()?;
+    if state.is_dry_run() {
+        let changed = state.dry_run()?;
+        return Ok(if changed { ExitCode::from(DRY_RUN_CHANGES) } else { ExitCode::SUCCESS });
+    }
+
+    let interval = match state.daemon_interval() {
+        Some(interval) => interval,
+        None => {
+            state.run()?;
+            return Ok(ExitCode::SUCCESS);
+        },
+    };
+
+    state.serve_metrics()?;
+    info!(interval = interval.as_secs(); "Running in daemon mode");
+
+    loop {
+        // a failed run shouldn't take the whole daemon down, the next one might succeed
+        if let Err(e) = state.run() {
+            error!("Failed to update the DNS records, retrying in {}s: {}", interval.as_secs(), describe(e.as_ref()));
+        }
+
+        sleep(interval);
+    }
+}
 
-    Ok(())
+fn main() -> ExitCode {
+    match run() {
+        Ok(code) => code,
+        Err(e) => {
+            // the logger isn't there yet if the settings themselves were the problem
+            if logging::is_initialized() {
+                error!("{}", describe(e.as_ref()));
+            } else {
+                eprintln!("Error: {}", describe(e.as_ref()));
+            }
+            ExitCode::FAILURE
+        },
+    }
 }
@@ -0,0 +1,117 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Result};
+use prometheus::{Encoder, Gauge, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder};
+
+// Figures collected throughout the pipeline, served over HTTP in daemon mode
+pub struct Metrics {
+    registry: Registry,
+
+    // labeled by state: discovered, online or filtered (left for Traefik querying by the filters),
+    // the Traefik endpoints outside of the tailnet are counted as "endpoints"
+    pub nodes: IntGaugeVec,
+    pub traefik_hosts_polled: IntGauge,
+    // labeled by node and result: success or failure
+    pub traefik_polls: IntCounterVec,
+    pub traefik_request_duration: HistogramVec,
+    pub routers: IntGauge,
+    // labeled by record type
+    pub records: IntGaugeVec,
+
+    last_write: Mutex<Option<Instant>>,
+    seconds_since_last_write: Gauge,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new_custom(Some("headscale_auto_dns".to_string()), None)?;
+
+        let nodes = IntGaugeVec::new(
+            Opts::new("nodes", "Headscale nodes found during the last run"), &["state"])?;
+        let traefik_hosts_polled = IntGauge::new(
+            "traefik_hosts_polled", "Traefik hosts queried during the last run")?;
+        let traefik_polls = IntCounterVec::new(
+            Opts::new("traefik_polls_total", "Traefik queries per node"), &["node", "result"])?;
+        let traefik_request_duration = HistogramVec::new(
+            HistogramOpts::new("traefik_request_duration_seconds", "Time spent querying a Traefik host"),
+            &["node"])?;
+        let routers = IntGauge::new("routers", "Traefik routers seen during the last run")?;
+        let records = IntGaugeVec::new(
+            Opts::new("records", "DNS records emitted during the last run"), &["type"])?;
+        let seconds_since_last_write = Gauge::new(
+            "seconds_since_last_write", "Seconds since the outputs were last successfully written")?;
+
+        registry.register(Box::new(nodes.clone()))?;
+        registry.register(Box::new(traefik_hosts_polled.clone()))?;
+        registry.register(Box::new(traefik_polls.clone()))?;
+        registry.register(Box::new(traefik_request_duration.clone()))?;
+        registry.register(Box::new(routers.clone()))?;
+        registry.register(Box::new(records.clone()))?;
+        registry.register(Box::new(seconds_since_last_write.clone()))?;
+
+        Ok(Metrics {
+            registry,
+            nodes,
+            traefik_hosts_polled,
+            traefik_polls,
+            traefik_request_duration,
+            routers,
+            records,
+            last_write: Mutex::new(None),
+            seconds_since_last_write,
+        })
+    }
+
+    pub fn observe_poll(&self, node: &str, success: bool, duration: Duration) {
+        let result = if success { "success" } else { "failure" };
+        self.traefik_polls.with_label_values(&[node, result]).inc();
+        self.traefik_request_duration.with_label_values(&[node]).observe(duration.as_secs_f64());
+    }
+
+    // A node that couldn't be queried at all, so there's no latency to speak of
+    pub fn observe_failure(&self, node: &str) {
+        self.traefik_polls.with_label_values(&[node, "failure"]).inc();
+    }
+
+    pub fn mark_written(&self) {
+        *self.last_write.lock().unwrap() = Some(Instant::now());
+    }
+
+    fn render(&self) -> Result<String> {
+        // this one only makes sense when computed at the time of scraping
+        match *self.last_write.lock().unwrap() {
+            Some(x) => self.seconds_since_last_write.set(x.elapsed().as_secs_f64()),
+            None => self.seconds_since_last_write.set(f64::NAN),
+        }
+
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+
+    // Serves /metrics on the given address from a background thread
+    pub fn serve(self: &Arc<Self>, address: &str) -> Result<()> {
+        let server = tiny_http::Server::http(address)
+            .map_err(|e| anyhow!("Unable to listen for metrics on {}: {}", address, e))?;
+        let metrics = Arc::clone(self);
+
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let response = match (request.url(), metrics.render()) {
+                    ("/metrics", Ok(body)) => tiny_http::Response::from_string(body)
+                        .with_header(tiny_http::Header::from_bytes(
+                            &b"Content-Type"[..], TextEncoder::new().format_type().as_bytes()).unwrap()),
+                    ("/metrics", Err(e)) => tiny_http::Response::from_string(e.to_string())
+                        .with_status_code(500),
+                    _ => tiny_http::Response::from_string("Not found").with_status_code(404),
+                };
+
+                let _ = request.respond(response);
+            }
+        });
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1140 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+use clap::{Args, ValueEnum};
+use serde::{Deserialize, Serialize};
+use anyhow::{bail, Context, Result};
+use log::warn;
+use crate::secret;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DnsRecord {
+    pub name:        String, // DNS domain we're trying to resolve
+    #[serde(rename = "type")] // we can't name it "type" in rust as its a reserved keyword
+    pub record_type: String, // Record type (A, AAAA or CNAME)
+    pub value:       String,
+}
+
+impl PartialEq for DnsRecord {
+    fn eq(&self, other: &Self) -> bool {
+        // a CNAME cannot coexist with any other record of the same name
+        if (self.name == other.name) &&
+            (self.record_type == other.record_type ||
+             self.record_type == "CNAME" || other.record_type == "CNAME") {
+            return true;
+        }
+        false
+    }
+}
+
+impl DnsRecord {
+    pub(crate) fn new(name: &str, record_type: &str, value: &str) -> DnsRecord {
+        DnsRecord { name: name.to_string(), record_type: record_type.to_string(), value: value.to_string() }
+    }
+
+    pub fn address(name: &str, ip: &str) -> DnsRecord {
+        DnsRecord::new(name, if ip.contains(':') { "AAAA" } else { "A" }, ip)
+    }
+
+    pub fn is_address(&self) -> bool {
+        self.record_type == "A" || self.record_type == "AAAA"
+    }
+
+    // eg. `*.apps.example.com`
+    pub fn is_wildcard(&self) -> bool {
+        self.name.starts_with("*.")
+    }
+
+    // Catches the typos in hand-written records that Headscale would choke on
+    fn check(&self) -> Result<()> {
+        let valid = match self.record_type.as_str() {
+            "A"     => self.value.parse::<Ipv4Addr>().is_ok(),
+            "AAAA"  => self.value.parse::<Ipv6Addr>().is_ok(),
+            "CNAME" => !self.value.is_empty(),
+            x => bail!(r#"Unsupported record type "{}" for "{}""#, x, self.name),
+        };
+
+        if self.name.is_empty() || !valid {
+            bail!(r#"Invalid {} record "{}" -> "{}""#, self.record_type, self.name, self.value);
+        }
+
+        Ok(())
+    }
+}
+
+// Hand-maintained records, either in Headscale's JSON format, the same thing
+// as YAML, or /etc/hosts syntax (anything that's not .json, .yaml or .yml)
+pub fn read_static_records(path: &str) -> Result<Vec<DnsRecord>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!(r#"Unable to read the static records "{}""#, path))?;
+
+    let records: Vec<DnsRecord> = if path.ends_with(".json") {
+        serde_json::from_str(&content)
+            .with_context(|| format!(r#"The static records "{}" are invalid"#, path))?
+    } else if path.ends_with(".yaml") || path.ends_with(".yml") {
+        serde_yaml::from_str(&content)
+            .with_context(|| format!(r#"The static records "{}" are invalid"#, path))?
+    } else {
+        parse_hosts(&content)
+            .with_context(|| format!(r#"The static records "{}" are invalid"#, path))?
+    };
+
+    for i in &records {
+        i.check().with_context(|| format!(r#"The static records "{}" are invalid"#, path))?;
+    }
+
+    Ok(records)
+}
+
+fn parse_hosts(content: &str) -> Result<Vec<DnsRecord>> {
+    let mut records = Vec::new();
+
+    for (number, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+
+        let Some(ip) = fields.next() else { continue };
+        if ip.parse::<IpAddr>().is_err() {
+            bail!(r#"Line {}: "{}" is not an IP address"#, number + 1, ip);
+        }
+
+        let names: Vec<&str> = fields.collect();
+        if names.is_empty() {
+            bail!("Line {}: no names given for {}", number + 1, ip);
+        }
+
+        for name in names {
+            records.push(DnsRecord::address(&name.to_lowercase(), ip));
+        }
+    }
+
+    Ok(records)
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    // Headscale's extra_records.json
+    Headscale,
+    // /etc/hosts syntax
+    Hosts,
+    // dnsmasq host-record= lines
+    Dnsmasq,
+    // Unbound local-data: lines
+    Unbound,
+    // a file for CoreDNS' hosts plugin (which is /etc/hosts syntax)
+    Coredns,
+    // BIND-style RFC 1035 zone file
+    Zone,
+    // dns.extra_records within Headscale's config.yaml, edited in place
+    HeadscaleConfig,
+}
+
+impl OutputFormat {
+    // Tailscale clients ignore anything but A and AAAA among Headscale's records,
+    // and hosts files are nothing but addresses
+    pub fn supports_cnames(&self) -> bool {
+        !matches!(self, OutputFormat::Headscale | OutputFormat::HeadscaleConfig |
+            OutputFormat::Hosts | OutputFormat::Coredns)
+    }
+
+    // The rest would either reject `*.domain` records or treat them literally
+    pub fn supports_wildcards(&self) -> bool {
+        matches!(self, OutputFormat::Zone | OutputFormat::Dnsmasq)
+    }
+}
+
+// Written as `format:path`, or just `path` for Headscale's JSON format
+#[derive(Clone, Debug)]
+pub struct Output {
+    pub format: OutputFormat,
+    pub path:   String,
+}
+
+impl FromStr for Output {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // only treat the prefix as a format if it's one we know, paths can have colons too
+        if let Some((format, path)) = s.split_once(':') {
+            if let Ok(format) = OutputFormat::from_str(format, true) {
+                return Ok(Output { format, path: path.to_string() });
+            }
+        }
+
+        Ok(Output { format: OutputFormat::Headscale, path: s.to_string() })
+    }
+}
+
+impl fmt::Display for Output {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.format {
+            OutputFormat::Headscale => write!(f, "{}", self.path),
+            format => write!(f, "{}:{}", format.to_possible_value().unwrap().get_name(), self.path),
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct OutputSettings {
+    #[arg(long = "output", short = 'o', env = "OUTPUT",
+        help = r#"Where the generated records will be written to, as a list of `format:path`.
+Supported formats: headscale, hosts, dnsmasq, unbound, coredns, zone, headscale-config.
+A path without a format is written as Headscale's extra_records.json,
+make sure you configure Headscale to read from this path."#,
+        value_delimiter = ',', default_value = "extra_records.json")]
+    pub outputs: Vec<Output>,
+
+    #[arg(long = "zone_origin", alias = "zo", env = "ZONE_ORIGIN",
+        help = "Origin of the generated zone file (eg. example.com), required by the zone format")]
+    zone_origin: Option<String>,
+
+    #[arg(long = "zone_nameserver", alias = "zns", env = "ZONE_NAMESERVER",
+        help = "Primary nameserver of the generated zone file (defaults to ns.<origin>)")]
+    zone_nameserver: Option<String>,
+
+    #[arg(long = "zone_ttl", alias = "zt", env = "ZONE_TTL",
+        help = "TTL of the records in the generated zone file", default_value_t = 300)]
+    zone_ttl: u32,
+
+    #[arg(long = "reload_command", alias = "rc", env = "RELOAD_COMMAND",
+        help = r#"Shell command that is run after all outputs have been written
+(eg. `systemctl reload headscale`). If it fails, the outputs are restored so it runs again next time"#)]
+    reload_command: Option<String>,
+}
+
+impl OutputSettings {
+    // `cnames` is whether the generated records will be CNAMEs
+    pub fn validate(&self, cnames: bool) -> Result<()> {
+        if self.zone_origin.is_none() && self.outputs.iter().any(|x| x.format == OutputFormat::Zone) {
+            bail!("The zone output format requires ZONE_ORIGIN to be set");
+        }
+
+        if let Some(output) = self.outputs.iter().find(|x| cnames && !x.format.supports_cnames()) {
+            bail!(r#"RECORD_MODE=cname can't be used with the "{}" output as it can't hold CNAME records,
+use the zone, dnsmasq or unbound formats instead"#, output);
+        }
+
+        Ok(())
+    }
+
+    // `existing` is what's currently in the output's file, if there's anything
+    pub fn render(&self, output: &Output, existing: Option<&str>, records: &[DnsRecord]) -> Result<String> {
+        let filtered: Vec<DnsRecord>;
+        let records = match output.format.supports_wildcards() {
+            true  => records,
+            false => {
+                filtered = records.iter().filter(|x| !x.is_wildcard()).cloned().collect();
+                &filtered
+            },
+        };
+
+        Ok(match output.format {
+            OutputFormat::Headscale => serde_json::to_string_pretty(records)?,
+            OutputFormat::Hosts | OutputFormat::Coredns => render_hosts(records),
+            OutputFormat::Dnsmasq => render_dnsmasq(records),
+            OutputFormat::Unbound => render_unbound(records),
+            OutputFormat::Zone => self.render_zone(records, existing)?,
+            OutputFormat::HeadscaleConfig => match existing {
+                Some(x) => splice_extra_records(x, records)
+                    .with_context(|| format!(r#"Unable to update Headscale's config file "{}""#, output.path))?,
+                None => bail!(r#"Unable to read Headscale's config file "{}""#, output.path),
+            },
+        })
+    }
+
+    // The records currently in the first Headscale output (JSON or config.yaml), as those
+    // are the formats that can be read back into records
+    pub fn read_existing(&self) -> Result<Vec<DnsRecord>> {
+        let output = self.outputs.iter()
+            .find(|x| matches!(x.format, OutputFormat::Headscale | OutputFormat::HeadscaleConfig))
+            .context("Comparing against the existing records requires a headscale or headscale-config output")?;
+
+        let content = match fs::read_to_string(&output.path) {
+            Ok(x) => x,
+            // nothing written yet, so everything is new
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && output.format == OutputFormat::Headscale =>
+                return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!(r#"Unable to read "{}""#, output.path)),
+        };
+
+        let invalid = || format!(r#""{}" does not contain valid Headscale records"#, output.path);
+        if output.format == OutputFormat::Headscale {
+            return serde_json::from_str(&content).with_context(invalid);
+        }
+
+        let mut config: serde_yaml::Value = serde_yaml::from_str(&content)
+            .with_context(|| format!(r#"Headscale's config file "{}" is not valid YAML"#, output.path))?;
+        match take_extra_records(&mut config) {
+            Some(x) if !x.is_null() => serde_yaml::from_value(x).with_context(invalid),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    // Writes every output whose contents would change, returns whether any of them did.
+    // `reload_pending` runs the reload command even if nothing changed, as the last one failed.
+    pub fn write_all(&self, records: &[DnsRecord], reload_pending: bool) -> Result<bool> {
+        // what the changed files held before, to put it back if the reload fails
+        let mut written: Vec<(&Output, Option<String>)> = Vec::new();
+
+        for output in &self.outputs {
+            let existing = fs::read_to_string(&output.path).ok();
+            let content = self.render(output, existing.as_deref(), records)?;
+
+            // don't touch the file (nor its mtime) if there's nothing new
+            if existing.as_deref() == Some(content.as_str()) { continue; }
+
+            write_atomically(Path::new(&output.path), &content)
+                .with_context(|| format!(r#"Unable to write to the output file "{}".
+Make sure that the output path is correct!"#, output.path))?;
+
+            written.push((output, existing));
+        }
+
+        let changed = !written.is_empty();
+        if let Some(command) = self.reload_command.as_ref().filter(|_| changed || reload_pending) {
+            if let Err(e) = run_reload_command(command) {
+                // otherwise the next run would find nothing to change and never reload again
+                for (output, previous) in written {
+                    let restored = match previous {
+                        Some(x) => write_atomically(Path::new(&output.path), &x),
+                        None => fs::remove_file(&output.path).map_err(|e| e.into()),
+                    };
+                    if let Err(e) = restored {
+                        warn!(output:% = output, error:% = e; "Unable to restore the output after the reload failed");
+                    }
+                }
+                return Err(e);
+            }
+        }
+
+        Ok(changed)
+    }
+
+    fn render_zone(&self, records: &[DnsRecord], existing: Option<&str>) -> Result<String> {
+        // the serial only moves if the records did, otherwise the file would change on every run
+        let old_serial = existing.and_then(parse_zone_serial);
+        if let Some(serial) = old_serial {
+            let content = self.render_zone_with_serial(records, serial)?;
+            if existing == Some(content.as_str()) { return Ok(content); }
+        }
+
+        // seconds since epoch fit into the 32-bit serial until 2106, good enough
+        let serial = (chrono::Utc::now().timestamp() as u32).max(old_serial.map_or(0, |x| x.wrapping_add(1)));
+
+        self.render_zone_with_serial(records, serial)
+    }
+
+    fn render_zone_with_serial(&self, records: &[DnsRecord], serial: u32) -> Result<String> {
+        let origin = match &self.zone_origin {
+            Some(x) => x.trim_end_matches('.').to_lowercase(),
+            None => bail!("The zone output format requires ZONE_ORIGIN to be set"),
+        };
+        let nameserver = match &self.zone_nameserver {
+            Some(x) => x.trim_end_matches('.').to_string(),
+            None => format!("ns.{}", origin),
+        };
+
+        let mut out = format!("$ORIGIN {origin}.\n$TTL {ttl}\n\
+@ IN SOA {nameserver}. hostmaster.{origin}. ( {serial} 3600 600 604800 {ttl} )\n\
+@ IN NS {nameserver}.\n", ttl = self.zone_ttl);
+
+        for i in records {
+            // anything outside of the zone would make the whole file invalid
+            if i.name != origin && !i.name.ends_with(&format!(".{}", origin)) { continue; }
+
+            out += &format!("{}. IN {} {}\n", i.name, i.record_type, dns_value(i));
+        }
+
+        Ok(out)
+    }
+}
+
+// Only understands the SOA line as we write it ourselves
+fn parse_zone_serial(zone: &str) -> Option<u32> {
+    let line = zone.lines().find(|x| x.starts_with("@ IN SOA "))?;
+    let (_, rest) = line.split_once('(')?;
+    rest.split_whitespace().next()?.parse().ok()
+}
+
+// Writes into a temporary file next to the destination and renames it over it,
+// so that whoever reads the file never sees a half-written one
+fn run_reload_command(command: &str) -> Result<()> {
+    let mut process = Command::new("sh");
+    for i in secret::ENV_VARS {
+        process.env_remove(i);
+    }
+
+    let status = process.arg("-c").arg(command).status()
+        .with_context(|| format!(r#"Unable to run the reload command "{}""#, command))?;
+
+    if !status.success() {
+        bail!(r#"The reload command "{}" failed ({})"#, command, status);
+    }
+
+    Ok(())
+}
+
+fn write_atomically(path: &Path, content: &str) -> Result<()> {
+    let dir = path.parent().filter(|x| !x.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let file_name = path.file_name().context("The output path is not a file")?;
+    let tmp = dir.join(format!(".{}.tmp", file_name.to_string_lossy()));
+
+    let existing = fs::metadata(path).ok();
+
+    // whatever a previous run might have left behind
+    let _ = fs::remove_file(&tmp);
+
+    // The file we're replacing might hold secrets (config.yaml does), so the temporary one is never
+    // more permissive than it, and it's owned and locked down the same way before anything goes in
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+        options.mode(existing.as_ref().map_or(0o666, |x| x.mode() & 0o777));
+    }
+    let mut file = options.open(&tmp)?;
+
+    if let Some(metadata) = &existing {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            // only possible as root (or when it's ours anyway), otherwise the file becomes ours
+            if let Err(e) = std::os::unix::fs::fchown(&file, Some(metadata.uid()), Some(metadata.gid())) {
+                warn!(path:% = path.display(), uid = metadata.uid(), gid = metadata.gid(), error:% = e;
+                    "Unable to keep the owner of the output file");
+            }
+        }
+        file.set_permissions(metadata.permissions())?;
+    }
+
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+
+    if let Err(e) = fs::rename(&tmp, path) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+
+    // make the rename itself durable as well
+    File::open(dir)?.sync_all()?;
+
+    Ok(())
+}
+
+fn render_hosts(records: &[DnsRecord]) -> String {
+    // hosts files are keyed by address, so all names sharing one go on the same line
+    // CNAMEs can't be expressed in this format at all
+    let mut by_address: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for i in records.iter().filter(|x| x.is_address()) {
+        by_address.entry(i.value.as_str()).or_default().push(i.name.as_str());
+    }
+
+    by_address.into_iter()
+        .map(|(address, names)| format!("{} {}\n", address, names.join(" ")))
+        .collect()
+}
+
+fn render_dnsmasq(records: &[DnsRecord]) -> String {
+    // a host-record line holds at most one IPv4 and one IPv6 address, any later one of
+    // the same family overwrites the earlier, so names with more get several lines
+    let mut by_name: BTreeMap<&str, (Vec<&str>, Vec<&str>)> = BTreeMap::new();
+    let mut out = String::new();
+    for i in records {
+        if let Some(domain) = i.name.strip_prefix("*.") {
+            // matches the domain itself as well, which is as close as dnsmasq gets
+            // (CNAMEs can't be wildcards in dnsmasq at all)
+            if i.is_address() {
+                out += &format!("address=/{}/{}\n", domain, i.value);
+            }
+        } else if i.is_address() {
+            let (v4, v6) = by_name.entry(i.name.as_str()).or_default();
+            match i.record_type == "A" {
+                true  => v4.push(i.value.as_str()),
+                false => v6.push(i.value.as_str()),
+            }
+        } else if i.record_type == "CNAME" {
+            out += &format!("cname={},{}\n", i.name, i.value);
+        }
+    }
+
+    let mut host_records = String::new();
+    for (name, (v4, v6)) in by_name {
+        for line in 0..v4.len().max(v6.len()) {
+            let addresses: Vec<&str> = v4.get(line).into_iter().chain(v6.get(line)).copied().collect();
+            host_records += &format!("host-record={},{}\n", name, addresses.join(","));
+        }
+    }
+
+    host_records + &out
+}
+
+fn render_unbound(records: &[DnsRecord]) -> String {
+    let mut out = String::from("server:\n");
+    for i in records {
+        out += &format!("    local-data: \"{}. IN {} {}\"\n", i.name, i.record_type, dns_value(i));
+    }
+    out
+}
+
+// CNAME targets have to be fully qualified in zone file syntax
+fn dns_value(record: &DnsRecord) -> String {
+    if record.record_type == "CNAME" {
+        record.value.clone() + "."
+    } else {
+        record.value.clone()
+    }
+}
+
+pub enum RecordChange {
+    Added(String, String, Vec<String>),
+    Removed(String, String, Vec<String>),
+    Changed(String, String, Vec<String>, Vec<String>),
+}
+
+impl fmt::Display for RecordChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordChange::Added(name, record_type, values) =>
+                write!(f, "+ {} {} {}", name, record_type, values.join(", ")),
+            RecordChange::Removed(name, record_type, values) =>
+                write!(f, "- {} {} {}", name, record_type, values.join(", ")),
+            RecordChange::Changed(name, record_type, old, new) =>
+                write!(f, "~ {} {} {} -> {}", name, record_type, old.join(", "), new.join(", ")),
+        }
+    }
+}
+
+// Compares records by their name and type, a name can have several values of the same type
+pub fn diff_records(old: &[DnsRecord], new: &[DnsRecord]) -> Vec<RecordChange> {
+    let group = |records: &[DnsRecord]| {
+        let mut grouped: BTreeMap<(String, String), Vec<String>> = BTreeMap::new();
+        for i in records {
+            grouped.entry((i.name.clone(), i.record_type.clone())).or_default().push(i.value.clone());
+        }
+        for values in grouped.values_mut() {
+            values.sort();
+            values.dedup();
+        }
+        grouped
+    };
+
+    let old = group(old);
+    let mut new = group(new);
+    let mut changes = Vec::new();
+
+    for ((name, record_type), old_values) in old {
+        match new.remove(&(name.clone(), record_type.clone())) {
+            None => changes.push(RecordChange::Removed(name, record_type, old_values)),
+            Some(new_values) if new_values != old_values =>
+                changes.push(RecordChange::Changed(name, record_type, old_values, new_values)),
+            Some(_) => {},
+        }
+    }
+
+    for ((name, record_type), values) in new {
+        changes.push(RecordChange::Added(name, record_type, values));
+    }
+
+    changes
+}
+
+fn indent_of(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+fn is_yaml_content(line: &str) -> bool {
+    let trimmed = line.trim_start();
+    !trimmed.is_empty() && !trimmed.starts_with('#')
+}
+
+// Whatever follows a key on its own line, without the comment
+fn inline_value(rest: &str) -> &str {
+    let rest = rest.trim();
+    match rest.starts_with('#') {
+        true  => "",
+        false => rest.split(" #").next().unwrap_or_default().trim_end(),
+    }
+}
+
+// The index of the line after a flow collection (`[...]` or `{...}`) that starts
+// at the given line and column, quoted strings and comments are skipped over
+fn flow_end(lines: &[&str], start: usize, column: usize) -> Result<usize> {
+    let mut depth = 0;
+    let mut quote: Option<char> = None;
+
+    for (i, line) in lines.iter().enumerate().skip(start) {
+        let mut chars = line[if i == start { column } else { 0 }..].chars().peekable();
+        let mut previous = ' ';
+
+        while let Some(c) = chars.next() {
+            match quote {
+                Some('"') if c == '\\' => { chars.next(); },
+                // '' is how a single quote is escaped within single quotes
+                Some('\'') if c == '\'' && chars.peek() == Some(&'\'') => { chars.next(); },
+                Some(x) if c == x => quote = None,
+                Some(_) => {},
+                None => match c {
+                    // quotes only start a string where a value can, not within eg. `it's`
+                    '"' | '\'' if previous.is_whitespace() || "[{,:".contains(previous) => quote = Some(c),
+                    '#' if previous.is_whitespace() => break,
+                    '[' | '{' => depth += 1,
+                    ']' | '}' => {
+                        depth -= 1;
+                        if depth == 0 { return Ok(i + 1); }
+                    },
+                    _ => {},
+                },
+            }
+            previous = c;
+        }
+    }
+
+    bail!("The flow collection starting on line {} is never closed", start + 1)
+}
+
+// Replaces (or adds) `dns.extra_records` in a Headscale config.yaml while keeping every other line,
+// comments included, exactly as they were. This is a line based edit rather than a YAML round trip
+// as no YAML library keeps the comments around, so the result is checked before it's used.
+fn splice_extra_records(existing: &str, records: &[DnsRecord]) -> Result<String> {
+    let spliced = splice_lines(existing, records)?;
+    check_splice(existing, &spliced, records)?;
+
+    Ok(spliced)
+}
+
+fn splice_lines(existing: &str, records: &[DnsRecord]) -> Result<String> {
+    let lines: Vec<&str> = existing.lines().collect();
+
+    let render = |indent: usize| -> Vec<String> {
+        let pad = " ".repeat(indent);
+        if records.is_empty() {
+            return vec![format!("{}extra_records: []", pad)];
+        }
+
+        // JSON strings happen to be valid double-quoted YAML strings
+        let quote = |x: &str| serde_json::to_string(x).unwrap();
+        let mut out = vec![format!("{}extra_records:", pad)];
+        for i in records {
+            out.push(format!("{}  - name: {}", pad, quote(&i.name)));
+            out.push(format!("{}    type: {}", pad, quote(&i.record_type)));
+            out.push(format!("{}    value: {}", pad, quote(&i.value)));
+        }
+        out
+    };
+
+    // returns whatever follows the key's colon
+    let key_rest = |line: &'_ str, key: &str| -> Option<usize> {
+        let trimmed = line.trim_start();
+        trimmed.strip_prefix(key)
+            .and_then(|x| x.strip_prefix(':'))
+            .filter(|x| x.is_empty() || x.starts_with(' ') || x.starts_with('#'))
+            .map(|_| indent_of(line) + key.len() + 1)
+    };
+
+    let mut out: Vec<String> = Vec::new();
+
+    let dns = match lines.iter().position(|x| indent_of(x) == 0 && key_rest(x, "dns").is_some()) {
+        Some(x) => x,
+        None => {
+            // no dns section at all, so just add one at the end
+            out.extend(lines.iter().map(|x| x.to_string()));
+            out.push("dns:".to_string());
+            out.extend(render(2));
+            return Ok(out.join("\n") + "\n");
+        },
+    };
+
+    match inline_value(&lines[dns]["dns:".len()..]) {
+        "" => {},
+        // nothing in there yet, so it can just as well become a block
+        "{}" | "null" | "~" => {
+            out.extend(lines[..dns].iter().map(|x| x.to_string()));
+            out.push("dns:".to_string());
+            out.extend(render(2));
+            out.extend(lines[dns + 1..].iter().map(|x| x.to_string()));
+            return Ok(out.join("\n") + "\n");
+        },
+        x => bail!(r#"The dns section is written inline ("{}") on line {}, only a block mapping
+(one key per line) can be edited"#, x, dns + 1),
+    }
+
+    // the dns section ends at the next top level key
+    let dns_end = lines[dns + 1..].iter()
+        .position(|x| is_yaml_content(x) && indent_of(x) == 0)
+        .map_or(lines.len(), |x| x + dns + 1);
+
+    let child_indent = lines[dns + 1..dns_end].iter()
+        .find(|x| is_yaml_content(x))
+        .map_or(2, |x| indent_of(x));
+
+    let key = lines[dns + 1..dns_end].iter()
+        .position(|x| indent_of(x) == child_indent && key_rest(x, "extra_records").is_some())
+        .map(|x| x + dns + 1);
+
+    match key {
+        Some(key) => {
+            let column = key_rest(lines[key], "extra_records").unwrap_or_default();
+            let value = inline_value(&lines[key][column..]);
+
+            let end = if value.starts_with('[') || value.starts_with('{') {
+                flow_end(&lines, key, column)?
+            } else {
+                // the value is everything indented deeper than the key (or a sequence at the same
+                // indentation), minus any trailing comments as those belong to whatever comes next
+                let mut end = key + 1;
+                let mut last_content = key + 1;
+                while end < dns_end {
+                    let line = lines[end];
+                    if is_yaml_content(line) {
+                        let indent = indent_of(line);
+                        if indent < child_indent ||
+                            (indent == child_indent && !line.trim_start().starts_with('-')) {
+                            break;
+                        }
+                        last_content = end + 1;
+                    }
+                    end += 1;
+                }
+                last_content
+            };
+
+            out.extend(lines[..key].iter().map(|x| x.to_string()));
+            out.extend(render(child_indent));
+            out.extend(lines[end..].iter().map(|x| x.to_string()));
+        },
+        None => {
+            out.extend(lines[..=dns].iter().map(|x| x.to_string()));
+            out.extend(render(child_indent));
+            out.extend(lines[dns + 1..].iter().map(|x| x.to_string()));
+        },
+    }
+
+    Ok(out.join("\n") + "\n")
+}
+
+// Takes dns.extra_records out of a parsed config, along with the dns section itself
+// if that leaves it empty, so that what remains can be compared
+fn take_extra_records(config: &mut serde_yaml::Value) -> Option<serde_yaml::Value> {
+    if config.is_null() {
+        *config = serde_yaml::Value::Mapping(serde_yaml::Mapping::new());
+    }
+    let root = config.as_mapping_mut()?;
+
+    let dns = root.get_mut("dns")?;
+    if dns.is_null() {
+        *dns = serde_yaml::Value::Mapping(serde_yaml::Mapping::new());
+    }
+    let taken = dns.as_mapping_mut()?.remove("extra_records");
+
+    if dns.as_mapping().is_some_and(|x| x.is_empty()) {
+        root.remove("dns");
+    }
+
+    taken
+}
+
+// Makes sure the line based edit did exactly what it was supposed to: the records
+// are in place and every other setting is still there, untouched
+fn check_splice(existing: &str, spliced: &str, records: &[DnsRecord]) -> Result<()> {
+    let mut before: serde_yaml::Value = serde_yaml::from_str(existing)
+        .context("The existing config file is not valid YAML")?;
+    let mut after: serde_yaml::Value = serde_yaml::from_str(spliced)
+        .context("Adding the records would have made the config file invalid")?;
+
+    if take_extra_records(&mut after) != Some(serde_yaml::to_value(records)?) {
+        bail!("Adding the records to the config file did not put them in dns.extra_records");
+    }
+
+    take_extra_records(&mut before);
+    if before != after {
+        bail!("Adding the records would have changed other settings of the config file");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records() -> Vec<DnsRecord> {
+        vec![
+            DnsRecord::new("app.example.com", "A", "100.64.0.1"),
+            DnsRecord::new("app.example.com", "AAAA", "fd7a::1"),
+            DnsRecord::new("www.example.com", "CNAME", "srv1.tailscale"),
+            DnsRecord::new("*.apps.example.com", "A", "100.64.0.2"),
+        ]
+    }
+
+    fn settings() -> OutputSettings {
+        OutputSettings {
+            outputs: Vec::new(),
+            zone_origin: Some("example.com".to_string()),
+            zone_nameserver: None,
+            zone_ttl: 300,
+            reload_command: None,
+        }
+    }
+
+    fn render(format: OutputFormat, records: &[DnsRecord]) -> String {
+        let output = Output { format, path: String::new() };
+        settings().render(&output, None, records).unwrap()
+    }
+
+    #[test]
+    fn hosts_format() {
+        // no CNAMEs nor wildcards, names sharing an address go on one line
+        let mut records = records();
+        records.push(DnsRecord::new("api.example.com", "A", "100.64.0.1"));
+        assert_eq!(render(OutputFormat::Hosts, &records),
+            "100.64.0.1 app.example.com api.example.com\nfd7a::1 app.example.com\n");
+        assert_eq!(render(OutputFormat::Coredns, &records), render(OutputFormat::Hosts, &records));
+    }
+
+    #[test]
+    fn dnsmasq_format() {
+        assert_eq!(render(OutputFormat::Dnsmasq, &records()), "\
+host-record=app.example.com,100.64.0.1,fd7a::1
+cname=www.example.com,srv1.tailscale
+address=/apps.example.com/100.64.0.2
+");
+
+        // one address per family and line, or dnsmasq only keeps the last one
+        let mut records = records();
+        records.push(DnsRecord::new("app.example.com", "A", "100.64.0.3"));
+        records.push(DnsRecord::new("api.example.com", "A", "100.64.0.1"));
+        records.push(DnsRecord::new("api.example.com", "A", "100.64.0.3"));
+        assert_eq!(render(OutputFormat::Dnsmasq, &records), "\
+host-record=api.example.com,100.64.0.1
+host-record=api.example.com,100.64.0.3
+host-record=app.example.com,100.64.0.1,fd7a::1
+host-record=app.example.com,100.64.0.3
+cname=www.example.com,srv1.tailscale
+address=/apps.example.com/100.64.0.2
+");
+    }
+
+    #[test]
+    fn unbound_format() {
+        assert_eq!(render(OutputFormat::Unbound, &records()), r#"server:
+    local-data: "app.example.com. IN A 100.64.0.1"
+    local-data: "app.example.com. IN AAAA fd7a::1"
+    local-data: "www.example.com. IN CNAME srv1.tailscale."
+"#);
+    }
+
+    #[test]
+    fn zone_format() {
+        let mut records = records();
+        records.push(DnsRecord::new("app.example.org", "A", "100.64.0.3"));
+        assert_eq!(settings().render_zone_with_serial(&records, 42).unwrap(), "\
+$ORIGIN example.com.
+$TTL 300
+@ IN SOA ns.example.com. hostmaster.example.com. ( 42 3600 600 604800 300 )
+@ IN NS ns.example.com.
+app.example.com. IN A 100.64.0.1
+app.example.com. IN AAAA fd7a::1
+www.example.com. IN CNAME srv1.tailscale.
+*.apps.example.com. IN A 100.64.0.2
+");
+    }
+
+    #[test]
+    fn zone_serial() {
+        let settings = settings();
+        let zone = settings.render_zone(&records(), None).unwrap();
+        let serial = parse_zone_serial(&zone).unwrap();
+
+        // unchanged records keep the file as it is, serial included
+        assert_eq!(settings.render_zone(&records(), Some(&zone)).unwrap(), zone);
+
+        let mut changed = records();
+        changed.pop();
+        let zone = settings.render_zone(&changed, Some(&zone)).unwrap();
+        assert!(parse_zone_serial(&zone).unwrap() > serial);
+
+        // the serial never goes backwards, even if the old one is ahead of the clock
+        let ahead = settings.render_zone_with_serial(&records(), 4_000_000_000).unwrap();
+        let zone = settings.render_zone(&changed, Some(&ahead)).unwrap();
+        assert_eq!(parse_zone_serial(&zone), Some(4_000_000_001));
+    }
+
+    fn splice(existing: &str) -> String {
+        splice_extra_records(existing, &records()[..2]).unwrap()
+    }
+
+    const SPLICED: &str = r#"  extra_records:
+    - name: "app.example.com"
+      type: "A"
+      value: "100.64.0.1"
+    - name: "app.example.com"
+      type: "AAAA"
+      value: "fd7a::1"
+"#;
+
+    #[test]
+    fn splice_upstream_example() {
+        // the layout of Headscale's config-example.yaml
+        let existing = r#"server_url: http://127.0.0.1:8080
+
+dns:
+  # Whether to use [MagicDNS](https://tailscale.com/kb/1081/magicdns/).
+  magic_dns: true
+  base_domain: example.com
+
+  nameservers:
+    global:
+      - 1.1.1.1
+    split:
+      {}
+
+  search_domains: []
+
+  # Extra DNS records
+  # so far only A and AAAA records are supported (on the tailscale side)
+  extra_records: []
+  #   - name: "grafana.myvpn.example.com"
+  #     type: "A"
+  #     value: "100.64.0.3"
+
+  # extra_records_path: /var/lib/headscale/extra-records.json
+
+# Unix socket used for the CLI to connect without authentication
+unix_socket: /var/run/headscale/headscale.sock
+"#;
+        let expected = existing.replace("  extra_records: []\n", SPLICED);
+        assert_eq!(splice(existing), expected);
+    }
+
+    #[test]
+    fn splice_empty_list() {
+        let existing = "dns:\n  magic_dns: true\n  extra_records: []\nlog:\n  level: info\n";
+        assert_eq!(splice(existing), format!("dns:\n  magic_dns: true\n{}log:\n  level: info\n", SPLICED));
+
+        assert_eq!(splice_extra_records(&splice(existing), &[]).unwrap(), existing);
+    }
+
+    #[test]
+    fn splice_same_indent_list() {
+        let existing = r#"dns:
+  extra_records:
+  - name: old.example.com
+    type: A
+    value: 100.64.0.9
+  # belongs to magic_dns
+  magic_dns: true
+"#;
+        assert_eq!(splice(existing), format!("dns:\n{}  # belongs to magic_dns\n  magic_dns: true\n", SPLICED));
+    }
+
+    #[test]
+    fn splice_flow_list() {
+        let existing = r#"dns:
+  extra_records: [ # one per line
+    {name: "odd]name.example.com", type: A, value: '100.64.0.9'},
+    {name: it's.example.com, type: A, value: 100.64.0.8}, # ]
+  ]
+  magic_dns: true
+"#;
+        assert_eq!(splice(existing), format!("dns:\n{}  magic_dns: true\n", SPLICED));
+
+        let existing = "dns:\n  extra_records: [{name: a.example.com, type: A, value: 100.64.0.9}]\n  magic_dns: true\n";
+        assert_eq!(splice(existing), format!("dns:\n{}  magic_dns: true\n", SPLICED));
+
+        let unclosed = "dns:\n  extra_records: [\n  magic_dns: true\n";
+        assert!(splice_extra_records(unclosed, &records()).is_err());
+    }
+
+    #[test]
+    fn splice_without_dns() {
+        assert_eq!(splice("server_url: http://127.0.0.1:8080\n"),
+            format!("server_url: http://127.0.0.1:8080\ndns:\n{}", SPLICED));
+        assert_eq!(splice(""), format!("dns:\n{}", SPLICED));
+    }
+
+    #[test]
+    fn splice_inline_dns() {
+        assert_eq!(splice("dns: {} # nothing yet\nlog: {}\n"), format!("dns:\n{}log: {{}}\n", SPLICED));
+
+        let error = splice_extra_records("dns: {magic_dns: true}\n", &records()).unwrap_err();
+        assert!(error.to_string().contains("inline"));
+    }
+
+    #[test]
+    fn splice_nested_key() {
+        // neither of those is dns.extra_records
+        let existing = r#"dns:
+  nameservers:
+    extra_records: nested
+  magic_dns: true
+policy:
+  extra_records: elsewhere
+"#;
+        let spliced = splice(existing);
+        assert_eq!(spliced, existing.replacen("dns:\n", &format!("dns:\n{}", SPLICED), 1));
+
+        let config: serde_yaml::Value = serde_yaml::from_str(&spliced).unwrap();
+        assert_eq!(config["dns"]["nameservers"]["extra_records"], "nested");
+        assert_eq!(config["policy"]["extra_records"], "elsewhere");
+    }
+
+    #[test]
+    fn splice_checks_result() {
+        // an edit that would break the file, or a file that's already broken, is refused
+        assert!(splice_extra_records("dns:\n  {magic_dns: true}\n", &records()).is_err());
+        assert!(splice_extra_records("dns: [\n", &records()).is_err());
+        assert!(check_splice("dns:\n  magic_dns: true\n", &format!("dns:\n{}", SPLICED), &records()[..2]).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn atomic_write_keeps_permissions() {
+        use std::os::unix::fs::{chown, MetadataExt, PermissionsExt};
+
+        let dir = std::env::temp_dir().join(format!("headscale-auto-dns-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.yaml");
+
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        // handing the file over to someone else only works as root
+        let root = fs::metadata(&path).unwrap().uid() == 0;
+        if root {
+            chown(&path, Some(1234), Some(1234)).unwrap();
+        }
+
+        write_atomically(&path, "new").unwrap();
+
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o640);
+        if root {
+            assert_eq!((metadata.uid(), metadata.gid()), (1234, 1234));
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_existing_config() {
+        let dir = std::env::temp_dir().join(format!("headscale-auto-dns-existing-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.yaml");
+        let settings = OutputSettings {
+            outputs: vec![
+                Output { format: OutputFormat::Hosts, path: dir.join("hosts").display().to_string() },
+                Output { format: OutputFormat::HeadscaleConfig, path: path.display().to_string() },
+            ],
+            ..settings()
+        };
+
+        assert!(settings.read_existing().is_err());
+
+        fs::write(&path, "server_url: https://headscale.example.com\ndns:\n  magic_dns: true\n").unwrap();
+        assert!(settings.read_existing().unwrap().is_empty());
+
+        let existing = fs::read_to_string(&path).unwrap();
+        fs::write(&path, splice_extra_records(&existing, &records()).unwrap()).unwrap();
+        // DnsRecord's PartialEq is about clashing records, not about being the same
+        assert_eq!(serde_json::to_string(&settings.read_existing().unwrap()).unwrap(),
+            serde_json::to_string(&records()).unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_reload_stays_pending() {
+        let dir = std::env::temp_dir().join(format!("headscale-auto-dns-reload-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("hosts");
+        let reloads = dir.join("reloads");
+        fs::write(&path, "old").unwrap();
+
+        let settings = |command: &str| OutputSettings {
+            outputs: vec![Output { format: OutputFormat::Hosts, path: path.display().to_string() }],
+            reload_command: Some(format!("echo >> {} && {}", reloads.display(), command)),
+            ..settings()
+        };
+        let reload_count = || fs::read_to_string(&reloads).map(|x| x.lines().count()).unwrap_or_default();
+
+        // the file goes back to what it was, so the next run writes it again
+        assert!(settings("exit 1").write_all(&records(), false).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "old");
+        assert_eq!(reload_count(), 1);
+
+        assert!(settings("true").write_all(&records(), false).unwrap());
+        assert_eq!(reload_count(), 2);
+
+        // nothing changes now, the reload only runs if the last one is still pending
+        assert!(!settings("true").write_all(&records(), false).unwrap());
+        assert_eq!(reload_count(), 2);
+        assert!(!settings("true").write_all(&records(), true).unwrap());
+        assert_eq!(reload_count(), 3);
+
+        // a file that wasn't there before is removed again
+        fs::remove_file(&path).unwrap();
+        assert!(settings("exit 1").write_all(&records(), false).is_err());
+        assert!(!path.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn diff() {
+        let old = vec![
+            DnsRecord::new("kept.example.com", "A", "100.64.0.1"),
+            DnsRecord::new("kept.example.com", "A", "100.64.0.2"),
+            DnsRecord::new("gone.example.com", "A", "100.64.0.1"),
+            DnsRecord::new("moved.example.com", "A", "100.64.0.1"),
+        ];
+        let new = vec![
+            // the order of the values doesn't matter, nor do repeated ones
+            DnsRecord::new("kept.example.com", "A", "100.64.0.2"),
+            DnsRecord::new("kept.example.com", "A", "100.64.0.1"),
+            DnsRecord::new("kept.example.com", "A", "100.64.0.1"),
+            DnsRecord::new("moved.example.com", "A", "100.64.0.2"),
+            DnsRecord::new("moved.example.com", "AAAA", "fd7a::2"),
+            DnsRecord::new("new.example.com", "CNAME", "srv1.tailscale"),
+        ];
+
+        let changes: Vec<String> = diff_records(&old, &new).iter().map(|x| x.to_string()).collect();
+        assert_eq!(changes, vec![
+            "- gone.example.com A 100.64.0.1",
+            "~ moved.example.com A 100.64.0.1 -> 100.64.0.2",
+            "+ moved.example.com AAAA fd7a::2",
+            "+ new.example.com CNAME srv1.tailscale",
+        ]);
+
+        assert!(diff_records(&new, &new).is_empty());
+        assert!(diff_records(&[], &[]).is_empty());
+    }
+
+    #[test]
+    fn hosts_file() {
+        let records = parse_hosts("\
+# managed by hand
+100.64.0.5   NAS.example.com nas.lan  # the NAS
+
+fd7a::5\tprinter.example.com
+").unwrap();
+        assert_eq!(records.iter().map(|x| (x.name.as_str(), x.record_type.as_str(), x.value.as_str())).collect::<Vec<_>>(),
+            vec![
+                ("nas.example.com", "A", "100.64.0.5"),
+                ("nas.lan", "A", "100.64.0.5"),
+                ("printer.example.com", "AAAA", "fd7a::5"),
+            ]);
+
+        let error = parse_hosts("100.64.0.5 nas.lan\n100.64.0.300 broken.lan\n").unwrap_err();
+        assert_eq!(error.to_string(), r#"Line 2: "100.64.0.300" is not an IP address"#);
+        let error = parse_hosts("nas.lan 100.64.0.5\n").unwrap_err();
+        assert_eq!(error.to_string(), r#"Line 1: "nas.lan" is not an IP address"#);
+        let error = parse_hosts("100.64.0.5 # no names\n").unwrap_err();
+        assert_eq!(error.to_string(), "Line 1: no names given for 100.64.0.5");
+    }
+}
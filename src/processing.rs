@@ -1,27 +1,185 @@
-use std::fs::File;
-use std::io::BufWriter;
-use clap::Parser;
+use clap::{Args, ValueEnum};
 use dotenv::dotenv;
-use serde::{Serialize};
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::net::{IpAddr, ToSocketAddrs};
 use std::rc::Rc;
-use anyhow::{Result, Context};
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use anyhow::{anyhow, bail, Result, Context};
 use regex::Regex;
+use serde::de::DeserializeOwned;
+use log::{debug, info, warn};
+use crate::describe;
 use crate::headscale::{headscale_user_list_contains_a_user, HeadscaleClient, HeadscaleNode, HeadscaleUser};
-use crate::traefik::{TraefikAPIClient, TraefikAPIClientDetails, TraefikRouter};
+use crate::logging::LoggingSettings;
+use crate::metrics::Metrics;
+use crate::rule::{self, RuleWarning};
+use crate::secret;
+use crate::settings::Settings;
+use crate::output::{diff_records, read_static_records, DnsRecord, OutputSettings};
+use crate::traefik::{TraefikAPIClient, TraefikAPIClientDetails, TraefikEndpoint, TraefikNodeOverride, TraefikProtocol,
+    TraefikRouter};
 
-#[derive(Parser)]
-struct ProcessingSetup {
+// What to do when a single Traefik host can't be queried
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum FailurePolicy {
+    // Abort the entire run, nothing gets written
+    FailFast,
+    // Skip the failed host and carry on with the rest
+    BestEffort,
+    // Reuse whatever the failed host returned during the previous run (daemon mode only)
+    KeepPrevious,
+}
+
+// Which routers to keep depending on whether they have TLS configured
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum TlsFilter {
+    Any,
+    Required,
+    Forbidden,
+}
+
+// Which node's records are published when several of them serve the same domain
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum ConflictPolicy {
+    // Whichever node Headscale listed first
+    FirstSeen,
+    // The node that comes first in CONFLICT_NODE_PRIORITY, unlisted nodes come last
+    Priority,
+    // Online nodes first, then the one seen most recently
+    Recent,
+    // Every node's addresses (round-robin), except for CNAMEs which can't be combined
+    All,
+}
+
+// Which address families get published
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum RecordFamily {
+    Both,
+    // A records only
+    Ipv4,
+    // AAAA records only
+    Ipv6,
+}
+
+impl RecordFamily {
+    fn allows(&self, ip: &str) -> bool {
+        match self {
+            RecordFamily::Both => true,
+            RecordFamily::Ipv4 => !ip.contains(':'),
+            RecordFamily::Ipv6 => ip.contains(':'),
+        }
+    }
+}
+
+// Which of a node's addresses Traefik is contacted on first, the rest are fallbacks
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum AddressPreference {
+    Ipv4First,
+    Ipv6First,
+    // the node's magicDNS name (node.tld), which requires this machine to be on the tailnet
+    MagicDns,
+}
+
+// What to do with HostRegexp matchers, which can't be turned into domains as they are
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum HostRegexpMode {
+    Ignore,
+    // Publish the hosts from HOST_REGEXP_CANDIDATES that the regex matches
+    Expand,
+    // Publish `*.domain` records (only the zone and dnsmasq outputs can express them)
+    Wildcard,
+}
+
+// Which side wins when a static record and a generated one share a name
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum StaticPrecedence {
+    Static,
+    Generated,
+}
+
+// How domains discovered through Traefik point to their node
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum RecordMode {
+    // A/AAAA records for every IP address of the node
+    Address,
+    // A CNAME to the node's magicDNS name, falling back to Address if there's none
+    // (or it doesn't resolve, see CNAME_VERIFY)
+    Cname,
+}
+
+#[derive(Args)]
+pub struct ProcessingSetup {
     #[arg(long = "traefik_middleware_whitelist", alias = "tmw", env = "TRAEFIK_MIDDLEWARE_WHITELIST",
         help = r#"What middlewares (if none, no filtering happens) need to be present
 in order for the domain to be added to the tailscale DNS"#, value_delimiter = ',',
         default_values_t = Vec::<String>::new())]
     middlewares: Vec<String>,
 
+    #[arg(long = "traefik_entrypoints", alias = "tep", env = "TRAEFIK_ENTRYPOINTS",
+        help = r#"Only use routers listening on at least one of these entry points
+(empty to allow all)"#, value_delimiter = ',', default_values_t = Vec::<String>::new())]
+    entry_points: Vec<String>,
+
+    #[arg(long = "traefik_excluded_entrypoints", alias = "teep", env = "TRAEFIK_EXCLUDED_ENTRYPOINTS",
+        help = "Ignore routers listening on any of these entry points", value_delimiter = ',',
+        default_values_t = Vec::<String>::new())]
+    excluded_entry_points: Vec<String>,
+
+    #[arg(long = "traefik_providers", alias = "tpr", env = "TRAEFIK_PROVIDERS",
+        help = r#"Only use routers coming from these providers (eg. docker, file)
+(empty to allow all)"#, value_delimiter = ',', default_values_t = Vec::<String>::new())]
+    providers: Vec<String>,
+
+    #[arg(long = "traefik_excluded_providers", alias = "texpr", env = "TRAEFIK_EXCLUDED_PROVIDERS",
+        help = "Ignore routers coming from any of these providers", value_delimiter = ',',
+        default_values_t = Vec::<String>::new())]
+    excluded_providers: Vec<String>,
+
+    #[arg(long = "traefik_router_whitelist", alias = "trw", env = "TRAEFIK_ROUTER_WHITELIST",
+        help = "A regex the router's name (eg. whoami@docker) needs to match in order to be used")]
+    router_whitelist_regex: Option<String>,
+
+    #[arg(long = "traefik_router_blacklist", alias = "trb", env = "TRAEFIK_ROUTER_BLACKLIST",
+        help = "A regex which excludes routers by their name (eg. whoami@docker)")]
+    router_blacklist_regex: Option<String>,
+
+    #[arg(long = "traefik_tls", alias = "ttls", env = "TRAEFIK_TLS",
+        help = "Whether to use only routers with TLS configured, only those without or any",
+        value_enum, default_value_t = TlsFilter::Any)]
+    tls: TlsFilter,
+
+    #[arg(long = "traefik_skip_disabled", alias = "tsd", env = "TRAEFIK_SKIP_DISABLED",
+        help = r#"Ignore routers that Traefik doesn't report as enabled
+(ie. disabled or with warnings because of a configuration error)"#, default_value_t = false)]
+    skip_disabled: bool,
+
+    #[arg(long = "traefik_skip_unhealthy", alias = "tsu", env = "TRAEFIK_SKIP_UNHEALTHY",
+        help = r#"Ignore routers whose service has no servers that are UP
+(asks Traefik for its services as well)"#, default_value_t = false)]
+    skip_unhealthy: bool,
+
     #[arg(long = "headscale_allowed_users", alias = "hs_au", env = "HEADSCALE_ALLOWED_USERS",
         help = r#"Filter machines that are queried thru Traefik based on their Tailscale user
  (empty to allow all machines)"#, value_delimiter = ',', default_values_t = Vec::<String>::new())]
     allowed_users: Vec<String>,
 
+    #[arg(long = "headscale_allowed_tags", alias = "hs_at", env = "HEADSCALE_ALLOWED_TAGS",
+        help = r#"Only query thru Traefik the machines having at least one of these ACL tags
+(eg. tag:server, empty to allow all), on top of the user filter"#, value_delimiter = ',',
+        default_values_t = Vec::<String>::new())]
+    allowed_tags: Vec<String>,
+
+    #[arg(long = "headscale_excluded_tags", alias = "hs_et", env = "HEADSCALE_EXCLUDED_TAGS",
+        help = "Never query thru Traefik the machines having any of these ACL tags", value_delimiter = ',',
+        default_values_t = Vec::<String>::new())]
+    excluded_tags: Vec<String>,
+
     #[arg(long = "headscale_blacklisted_nodes", alias = "hs_bn", env = "HEADSCALE_BLACKLISTED_NODES",
         help = r#"Filter out machines for Traefik querying based on their Tailscale hostname
   (empty to allow all)"#, value_delimiter = ',', default_values_t = Vec::<String>::new())]
@@ -37,15 +195,141 @@ The whitelist is processed first."#)]
 The blacklist is processed last."#)]
     domain_blacklist_regex: Option<String>,
 
-    #[arg(long = "output", short = 'o', env = "OUTPUT",
-        help = r#"Path where the generated json extra_records.json will be written to.
-Make sure you configure Headscale to read from this path."#, default_value = "extra_records.json")]
-    output_path: String,
+    #[command(flatten)]
+    output: OutputSettings,
+
+    #[command(flatten)]
+    pub logging: LoggingSettings,
 
     #[arg(long = "headscale_old_magicdns", alias = "hs_olddns", env = "HEADSCALE_OLD_MAGICDNS",
         help = r#"Provide old magicDNS functionality to Headscale,
 ie. the old `node.user.base_domain` format"#, default_value_t = true)]
     old_magicdns: bool,
+
+    #[arg(long = "record_mode", alias = "rm", env = "RECORD_MODE",
+        help = r#"Whether Traefik domains become A/AAAA records of the node's addresses
+or a CNAME pointing to the node's magicDNS name (node.tld). CNAMEs need outputs
+that can hold them, which Headscale's own formats and hosts files can't"#,
+        value_enum, default_value_t = RecordMode::Address)]
+    record_mode: RecordMode,
+
+    #[arg(long = "traefik_record_family", alias = "trf", env = "TRAEFIK_RECORD_FAMILY",
+        help = "Which address records (both, ipv4 or ipv6) to publish for domains found through Traefik",
+        value_enum, default_value_t = RecordFamily::Both)]
+    traefik_family: RecordFamily,
+
+    #[arg(long = "magicdns_record_family", alias = "mrf", env = "MAGICDNS_RECORD_FAMILY",
+        help = "Which address records (both, ipv4 or ipv6) to publish for old magicDNS names",
+        value_enum, default_value_t = RecordFamily::Both)]
+    magicdns_family: RecordFamily,
+
+    #[arg(long = "cname_verify", alias = "cv", env = "CNAME_VERIFY",
+        help = r#"Only emit a CNAME if its target can be resolved from this machine
+(which should be a member of the tailnet), otherwise fall back to A/AAAA records"#,
+        default_value_t = true)]
+    cname_verify: bool,
+
+    #[arg(long = "host_regexp_mode", alias = "hrm", env = "HOST_REGEXP_MODE",
+        help = r#"How HostRegexp matchers are published: not at all, by matching them against
+HOST_REGEXP_CANDIDATES, or as wildcard records (zone and dnsmasq outputs only)"#,
+        value_enum, default_value_t = HostRegexpMode::Ignore)]
+    host_regexp_mode: HostRegexpMode,
+
+    #[arg(long = "host_regexp_candidates", alias = "hrc", env = "HOST_REGEXP_CANDIDATES",
+        help = "Host names that HostRegexp matchers are tried against in the expand mode",
+        value_delimiter = ',', default_values_t = Vec::<String>::new())]
+    host_regexp_candidates: Vec<String>,
+
+    #[arg(long = "static_records", alias = "sr", env = "STATIC_RECORDS",
+        help = r#"File with hand-maintained records to merge into the output, either Headscale's JSON
+format, the same as YAML (.yaml/.yml) or /etc/hosts syntax (anything else)"#)]
+    static_records: Option<String>,
+
+    #[arg(long = "static_records_precedence", alias = "srp", env = "STATIC_RECORDS_PRECEDENCE",
+        help = "Whether static or generated records win when both define the same name",
+        value_enum, default_value_t = StaticPrecedence::Static)]
+    static_precedence: StaticPrecedence,
+
+    #[arg(long = "traefik_node_overrides", alias = "tno", env = "TRAEFIK_NODE_OVERRIDES",
+        help = r#"Path to a JSON file (or inline JSON) with per-node Traefik connection settings
+(scheme, port, path, user, password or password_file), keyed by node name or by "tag:name""#)]
+    traefik_overrides_path: Option<String>,
+
+    #[arg(long = "traefik_endpoints", alias = "tend", env = "TRAEFIK_ENDPOINTS",
+        help = r#"Path to a JSON file (or inline JSON) with Traefik instances outside of the tailnet,
+keyed by a name no Headscale node has, each with a "url", the "addresses" its domains
+point to and optionally its own "user" and "password" (or "password_file"), as node
+overrides don't apply to them"#)]
+    traefik_endpoints_path: Option<String>,
+
+    #[arg(long = "traefik_http_routers", alias = "thr", env = "TRAEFIK_HTTP_ROUTERS",
+        help = "Generate DNS records from Traefik's HTTP routers (Host rules)", default_value_t = true)]
+    http_routers: bool,
+
+    #[arg(long = "traefik_tcp_routers", alias = "ttr", env = "TRAEFIK_TCP_ROUTERS",
+        help = "Generate DNS records from Traefik's TCP routers (HostSNI rules)", default_value_t = true)]
+    tcp_routers: bool,
+
+    #[arg(long = "traefik_preferred_nodes", alias = "tpn", env = "TRAEFIK_PREFERRED_NODES",
+        help = r#"Nodes whose Traefik is queried first, in this order, so that they win
+when several nodes serve the same domain (with the first-seen conflict policy)"#,
+        value_delimiter = ',', default_values_t = Vec::<String>::new())]
+    preferred_nodes: Vec<String>,
+
+    #[arg(long = "traefik_standby", alias = "ts", env = "TRAEFIK_STANDBY",
+        help = r#"Only use a single node's Traefik: the first of TRAEFIK_PREFERRED_NODES that is online
+and reachable. The rest of them, followed by every other node, are standbys that are queried
+in turn only when the ones before them fail"#,
+        default_value_t = false)]
+    standby: bool,
+
+    #[arg(long = "conflict_policy", alias = "cp", env = "CONFLICT_POLICY",
+        help = r#"Which node's records are published when several nodes serve the same domain:
+the first one seen, by CONFLICT_NODE_PRIORITY, the most recently seen one or all of them
+(domains involving a CNAME fall back to the first one seen, as it can't be combined)"#,
+        value_enum, default_value_t = ConflictPolicy::FirstSeen)]
+    conflict_policy: ConflictPolicy,
+
+    #[arg(long = "conflict_node_priority", alias = "cnp", env = "CONFLICT_NODE_PRIORITY",
+        help = "Node names, most preferred first, for the priority conflict policy",
+        value_delimiter = ',', default_values_t = Vec::<String>::new())]
+    node_priority: Vec<String>,
+
+    #[arg(long = "traefik_address_preference", alias = "tap", env = "TRAEFIK_ADDRESS_PREFERENCE",
+        help = r#"Which address of a node to reach Traefik on first, the node's other addresses
+are tried in turn if connecting fails"#,
+        value_enum, default_value_t = AddressPreference::Ipv4First)]
+    address_preference: AddressPreference,
+
+    #[arg(long = "traefik_concurrency", alias = "tc", env = "TRAEFIK_CONCURRENCY",
+        help = "Maximum amount of Traefik hosts that are queried at the same time",
+        default_value_t = 8, value_parser = clap::value_parser!(u64).range(1..))]
+    concurrency: u64,
+
+    #[arg(long = "failure_policy", alias = "fp", env = "FAILURE_POLICY",
+        help = r#"What to do when a Traefik host cannot be queried:
+abort the run, skip the host or keep its records from the previous run"#,
+        value_enum, default_value_t = FailurePolicy::BestEffort)]
+    failure_policy: FailurePolicy,
+
+    #[arg(long = "dry_run", aliases = ["dr", "dry-run"], env = "DRY_RUN",
+        help = r#"Don't write anything, print how the records would change compared to the
+Headscale output instead. Exits with code 3 if there are any changes (1 and 2 are errors)"#, default_value_t = false)]
+    dry_run: bool,
+
+    #[arg(long = "metrics_listen", alias = "ml", env = "METRICS_LISTEN",
+        help = "Address to serve Prometheus metrics on at /metrics, eg. 127.0.0.1:9184 (only used in daemon mode)")]
+    metrics_listen: Option<String>,
+
+    #[arg(long = "daemon", short = 'd', env = "DAEMON",
+        help = r#"Keep running in the background and regenerate the records
+every `interval` seconds instead of exiting after a single run"#, default_value_t = false)]
+    daemon: bool,
+
+    #[arg(long = "interval", short = 'i', env = "INTERVAL",
+        help = "Amount of seconds to wait between each run (only used in daemon mode)",
+        default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    interval: u64,
 }
 
 // values that are expected to change during runtime
@@ -56,8 +340,18 @@ struct ProcessingVolatile {
     // This ONLY includes users that are searched for when searching for Traefik endpoints,
     // may be changed in the future to behave in the same way as nodes does.
     headscale_users: Vec<HeadscaleUser>,
-    traefik_clients: Vec<(TraefikAPIClient, Rc<HeadscaleNode>)>,
+    // every node gets a client per address, tried in order
+    traefik_clients: Vec<(NodeClients, Rc<HeadscaleNode>)>,
     traefik_router:  Vec<(TraefikRouter,    Rc<HeadscaleNode>)>,
+    // Traefik hosts that failed during the current run
+    failures: Vec<NodeFailure>,
+    // writing the outputs or reloading failed, so the reload has to run again even if they don't change
+    reload_pending: bool,
+}
+
+struct NodeFailure {
+    node:  String,
+    error: String,
 }
 // basic wrapper impl just to make rust behave
 impl ProcessingVolatile {
@@ -67,6 +361,8 @@ impl ProcessingVolatile {
             headscale_nodes: Vec::new(),
             traefik_clients: Vec::new(),
             traefik_router:  Vec::new(),
+            failures:        Vec::new(),
+            reload_pending:  false,
         }
     }
 }
@@ -77,7 +373,13 @@ pub struct Processing {
     headscale_client: HeadscaleClient,
     domain_whitelist: Option<Regex>,
     domain_blacklist: Option<Regex>,
+    router_whitelist: Option<Regex>,
+    router_blacklist: Option<Regex>,
     volatile: ProcessingVolatile,
+    metrics: Arc<Metrics>,
+    traefik_overrides: BTreeMap<String, TraefikNodeOverride>,
+    traefik_endpoints: BTreeMap<String, TraefikEndpoint>,
+    traefik_details: TraefikAPIClientDetails,
 }
 
 impl Processing {
@@ -85,29 +387,169 @@ impl Processing {
         // Load in the dotenv variables
         dotenv().ok();
 
-        let setup = ProcessingSetup::parse();
+        let settings = Settings::load()?;
+        let mut setup = settings.processing;
+
+        // `HEADSCALE_ALLOWED_USERS=` and such mean "no filter", not "only the user without a name"
+        setup.allowed_users.retain(|x| !x.is_empty());
+        setup.allowed_tags.retain(|x| !x.is_empty());
+        setup.excluded_tags.retain(|x| !x.is_empty());
+        setup.host_regexp_candidates.retain(|x| !x.is_empty());
+        setup.preferred_nodes.retain(|x| !x.is_empty());
+        for i in &mut setup.host_regexp_candidates {
+            *i = i.to_lowercase();
+        }
+
+        if setup.standby && setup.preferred_nodes.is_empty() {
+            warn!("TRAEFIK_STANDBY is set without TRAEFIK_PREFERRED_NODES, \
+                the first node Headscale lists is the primary");
+        }
+
+        // catch whatever we can before talking to anything
+        setup.output.validate(setup.record_mode == RecordMode::Cname)?;
+
+        let allow_world_readable = settings.allow_world_readable_secrets;
+
+        // the environment and the command line win over the tables of the config file
+        let mut traefik_overrides: BTreeMap<String, TraefikNodeOverride> = match settings.traefik_overrides {
+            Some(x) if setup.traefik_overrides_path.is_none() => x,
+            _ => read_json_setting(setup.traefik_overrides_path.as_deref(), "Traefik node overrides",
+                allow_world_readable)?,
+        };
+        for (name, node_override) in &mut traefik_overrides {
+            node_override.resolve_secrets(allow_world_readable)
+                .with_context(|| format!(r#"Unable to read the password of the Traefik node override "{}""#, name))?;
+        }
+
+        let mut traefik_endpoints: BTreeMap<String, TraefikEndpoint> = match settings.traefik_endpoints {
+            Some(x) if setup.traefik_endpoints_path.is_none() => x,
+            _ => read_json_setting(setup.traefik_endpoints_path.as_deref(), "Traefik endpoints",
+                allow_world_readable)?,
+        };
+        for (name, endpoint) in &mut traefik_endpoints {
+            endpoint.resolve_secrets(allow_world_readable)
+                .with_context(|| format!(r#"Unable to read the password of the Traefik endpoint "{}""#, name))?;
+            if endpoint.addresses.is_empty() {
+                bail!(r#"The Traefik endpoint "{}" has no addresses"#, name);
+            }
+            for i in &endpoint.addresses {
+                i.parse::<IpAddr>()
+                    .with_context(|| format!(r#"The Traefik endpoint "{}" has an invalid address "{}""#, name, i))?;
+            }
+            if traefik_overrides.contains_key(name) {
+                warn!(endpoint = name.as_str(); "Traefik node overrides don't apply to endpoints, set them on the endpoint");
+            }
+        }
+        settings.traefik.validate().map_err(|e| anyhow!("{}", e))?;
+
+        let compile = |regex: &Option<String>, what: &str| -> Result<Option<Regex>> {
+            match regex {
+                Some(r) => Ok(Some(Regex::new(r).with_context(|| format!("The {} regex is invalid", what))?)),
+                None    => Ok(None),
+            }
+        };
+        let domain_whitelist = compile(&setup.domain_whitelist_regex, "whitelist")?;
+        let domain_blacklist = compile(&setup.domain_blacklist_regex, "blacklist")?;
+        let router_whitelist = compile(&setup.router_whitelist_regex, "router whitelist")?;
+        let router_blacklist = compile(&setup.router_blacklist_regex, "router blacklist")?;
+        let metrics = Arc::new(Metrics::new()?);
+
+        // the first thing that talks to anything, so it goes last
+        let headscale_client = HeadscaleClient::new(settings.headscale)
+            .context("Failed to initialize the Headscale client")?;
 
         Ok(Self {
-            headscale_client: HeadscaleClient::new()
-                .context("Failed to initialize the Headscale client")?,
+            headscale_client,
+            traefik_details: settings.traefik,
             volatile: ProcessingVolatile::new(),
-            domain_whitelist: match &setup.domain_whitelist_regex {
-                Some(r) => Some(Regex::new(r).context("The whitelist regex is invalid")?),
-                None    => None,
-            },
-            domain_blacklist: match &setup.domain_blacklist_regex {
-                Some(r) => Some(Regex::new(r).context("The blacklist regex is invalid")?),
-                None    => None,
-            },
+            metrics,
+            traefik_overrides,
+            traefik_endpoints,
+            domain_whitelist,
+            domain_blacklist,
+            router_whitelist,
+            router_blacklist,
             setup,
         })
     }
 
+    // Returns how long to wait between runs, if we're supposed to keep running at all
+    pub fn daemon_interval(&self) -> Option<Duration> {
+        if self.setup.daemon {
+            Some(Duration::from_secs(self.setup.interval))
+        } else {
+            None
+        }
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.setup.dry_run
+    }
+
+    // Runs the whole pipeline without writing anything, printing the record-level
+    // changes instead. Returns whether there were any changes.
+    pub fn dry_run(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
+        self.volatile.failures = Vec::new();
+
+        self.update_servers()?;
+        self.update_routers()?;
+
+        let existing = self.setup.output.read_existing()?;
+        // the Headscale output can't hold wildcards, so they'd always show up as new
+        let records: Vec<DnsRecord> = self.generate_records()?.into_iter()
+            .filter(|x| !x.is_wildcard()).collect();
+        let changes = diff_records(&existing, &records);
+
+        for i in &changes {
+            println!("{}", i);
+        }
+
+        self.report_failures();
+
+        Ok(!changes.is_empty())
+    }
+
+    // Starts serving /metrics if it has been set up (daemon mode only)
+    pub fn serve_metrics(&self) -> Result<()> {
+        match &self.setup.metrics_listen {
+            Some(address) => self.metrics.serve(address),
+            None => Ok(()),
+        }
+    }
+
+    // A single pass of the entire pipeline, the client and regexes are reused between passes.
+    // Returns whether the records have changed since the last time they were written.
+    pub fn run(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
+        self.volatile.failures = Vec::new();
+
+        self.update_servers()?;
+        self.update_routers()?;
+        let changed = self.generate_json()?;
+
+        self.report_failures();
+
+        Ok(changed)
+    }
+
+    fn report_failures(&self) {
+        if self.volatile.failures.is_empty() { return; }
+
+        let outcome = match self.setup.failure_policy {
+            FailurePolicy::KeepPrevious => "kept their previous records",
+            _ => "were skipped",
+        };
+
+        warn!("{} Traefik host(s) could not be queried and {}", self.volatile.failures.len(), outcome);
+        for i in &self.volatile.failures {
+            warn!(node = i.node.as_str(), error = i.error.as_str(); "Traefik host failed");
+        }
+    }
+
     pub fn update_servers(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         self.volatile.headscale_users = self.headscale_client.get_user_list()?;
 
         // if user filtering is enabled
-        if self.setup.allowed_users.len() > 0 {
+        if !self.setup.allowed_users.is_empty() {
             self.volatile.headscale_users.retain(|x| self.setup.allowed_users.contains(&x.name));
         }
 
@@ -116,42 +558,209 @@ impl Processing {
 
         // Turn it into a reference counted list
         self.volatile.headscale_nodes = all_headscale_nodes.into_iter()
-            .map(|x| Rc::new(x)).collect();
+            .map(Rc::new).collect();
 
         // Create a second list that only contains a list of nodes that are
         // in the interest of Traefik only and drop the ones that are offline
-        let mut traefik_only_node_list: Vec<Rc<HeadscaleNode>> = self.volatile.headscale_nodes.iter()
-            .filter(|&x| headscale_user_list_contains_a_user(
-                        &self.volatile.headscale_users, x.user.name.as_str()
-            ) && x.online).map(|x| Rc::clone(x)).collect();
+        let mut traefik_only_node_list: Vec<Rc<HeadscaleNode>> = Vec::new();
+        for i in &self.volatile.headscale_nodes {
+            let tags = i.get_tags();
+            let has_tag = |list: &Vec<String>| list.iter().any(|x| tags.contains(&normalize_tag(x).as_str()));
 
-        // Filter out any undesired nodes
-        if self.setup.node_blacklist.len() > 0 {
-            traefik_only_node_list.retain(|x| !self.setup.node_blacklist.contains(&x.given_name));
+            let skip_reason = if !headscale_user_list_contains_a_user(&self.volatile.headscale_users, i.user.name.as_str()) {
+                Some("user is not allowed")
+            } else if !self.setup.allowed_tags.is_empty() && !has_tag(&self.setup.allowed_tags) {
+                Some("node has no allowed tag")
+            } else if has_tag(&self.setup.excluded_tags) {
+                Some("node has an excluded tag")
+            } else if !i.online {
+                Some("node is offline")
+            } else if i.ip_addresses.is_empty() {
+                Some("node has no addresses")
+            // Filter out any undesired nodes
+            } else if self.setup.node_blacklist.contains(&i.given_name) {
+                Some("node is blacklisted")
+            } else {
+                None
+            };
+
+            match skip_reason {
+                Some(reason) => debug!(node = i.given_name.as_str(), user = i.user.name.as_str(), reason;
+                    "Skipping node for Traefik querying"),
+                None => {
+                    info!(node = i.given_name.as_str(), user = i.user.name.as_str(); "Selected node for Traefik querying");
+                    traefik_only_node_list.push(Rc::clone(i));
+                },
+            }
         }
 
+        let online = self.volatile.headscale_nodes.iter().filter(|x| x.online).count();
+        self.metrics.nodes.with_label_values(&["discovered"]).set(self.volatile.headscale_nodes.len() as i64);
+        self.metrics.nodes.with_label_values(&["online"]).set(online as i64);
+        self.metrics.nodes.with_label_values(&["filtered"]).set(traefik_only_node_list.len() as i64);
+
+        // endpoints share the names of the nodes (overrides, preferred nodes, conflicts and metrics
+        // all go by them), so one that's named like a node would be mistaken for it
+        let mut endpoints = 0;
+        for (name, endpoint) in &self.traefik_endpoints {
+            if self.volatile.headscale_nodes.iter().any(|x| x.given_name == *name) {
+                let error = "a Headscale node has the same name, rename the endpoint".to_string();
+                if self.setup.failure_policy == FailurePolicy::FailFast {
+                    return Err(format!("Unable to use the Traefik endpoint {}: {}", name, error).into());
+                }
+                self.volatile.failures.push(NodeFailure { node: name.clone(), error });
+                continue;
+            }
+
+            info!(node = name.as_str(), url = endpoint.url.as_str(); "Selected endpoint for Traefik querying");
+            traefik_only_node_list.push(Rc::new(HeadscaleNode::external(name, &endpoint.addresses)));
+            endpoints += 1;
+        }
+        self.metrics.nodes.with_label_values(&["endpoints"]).set(endpoints);
+
+        // preferred nodes go first, sort_by_key keeps the rest in Headscale's order
+        traefik_only_node_list.sort_by_key(|x| self.preferred_position(x));
+
         // Generate a list of Traefik clients using the the smaller list we just made
         self.volatile.traefik_clients = Vec::new();
         for i in traefik_only_node_list {
-            let details = TraefikAPIClientDetails::from_custom_host(
-                // either way, IPv4 or IPv6 would work here so we don't really care
-                i.ip_addresses[0].as_str()
-            )?;
-            let client = TraefikAPIClient::from(&details)?;
+            // the node's own override wins over the ones of its tags, endpoints bring their own settings
+            let node_override = match i.external {
+                true  => None,
+                false => self.traefik_overrides.get(&i.given_name)
+                    .or_else(|| i.get_tags().iter().find_map(|x| self.traefik_overrides.get(*x))),
+            };
+            if node_override.is_some() {
+                debug!(node = i.given_name.as_str(); "Using Traefik connection overrides");
+            }
+
+            let details: Vec<TraefikAPIClientDetails> = match self.traefik_endpoints.get(&i.given_name) {
+                Some(endpoint) if i.external => vec![self.traefik_details.with_endpoint(endpoint)],
+                _ => self.traefik_hosts(&i).iter().map(|x| self.traefik_details.with_host(x)).collect(),
+            };
+
+            let clients = details.into_iter()
+                .map(|mut details| {
+                    if let Some(node_override) = node_override {
+                        details.apply_override(node_override);
+                    }
+                    TraefikAPIClient::from(&details)
+                })
+                .collect::<Result<Vec<_>, _>>();
+
+            // the node is kept around so that it fails like any other node would in update_routers
+            let clients = match clients {
+                Ok(clients) => Ok(clients),
+                Err(e) if self.setup.failure_policy == FailurePolicy::FailFast =>
+                    return Err(format!("Unable to set up the Traefik client for {}: {}",
+                        i.given_name, describe(e.as_ref())).into()),
+                Err(e) => Err(format!("Unable to set up the Traefik client: {}", describe(e.as_ref()))),
+            };
 
-            self.volatile.traefik_clients.push((client, Rc::clone(&i)));
+            self.volatile.traefik_clients.push((clients, Rc::clone(&i)));
         }
 
         Ok(())
     }
 
+    fn preferred_position(&self, node: &HeadscaleNode) -> usize {
+        self.setup.preferred_nodes.iter().position(|x| *x == node.given_name).unwrap_or(usize::MAX)
+    }
+
+    // The hosts a node's Traefik can be reached on, in the preferred order
+    fn traefik_hosts(&self, node: &HeadscaleNode) -> Vec<String> {
+        // Headscale might give them in CIDR notation, and IPv6 needs brackets within URLs
+        let addresses: Vec<IpAddr> = node.ip_addresses.iter()
+            .filter_map(|x| x.split('/').next()?.parse().ok())
+            .collect();
+        let v4 = addresses.iter().filter(|x| x.is_ipv4()).map(|x| x.to_string());
+        let v6 = addresses.iter().filter(|x| x.is_ipv6()).map(|x| format!("[{}]", x));
+
+        match self.setup.address_preference {
+            AddressPreference::Ipv4First => v4.chain(v6).collect(),
+            AddressPreference::Ipv6First => v6.chain(v4).collect(),
+            AddressPreference::MagicDns => node.get_magic_dns_fqdn(&self.headscale_client).into_iter()
+                .chain(v4).chain(v6).collect(),
+        }
+    }
+
     pub fn update_routers(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         let mut all_routers: Vec<(TraefikRouter, Rc<HeadscaleNode>)> = Vec::new();
 
-        for (client, node) in &self.volatile.traefik_clients {
-            let mut routers = TraefikAPIClient::get_router_list(&client)?;
+        let mut protocols = Vec::new();
+        if self.setup.http_routers { protocols.push(TraefikProtocol::Http); }
+        if self.setup.tcp_routers  { protocols.push(TraefikProtocol::Tcp); }
+
+        let previous_routers = std::mem::take(&mut self.volatile.traefik_router);
+
+        let clients = &self.volatile.traefik_clients;
+        let mut results: Vec<Option<FetchResult>> = (0..clients.len()).map(|_| None).collect();
+        let outcomes = |results: &[Option<FetchResult>]| results.iter()
+            .map(|x| x.as_ref().map(|(x, _)| x.is_ok())).collect::<Vec<_>>();
+
+        // Query everything up front in parallel, the results are processed in order
+        // afterwards so that deduplication behaves the same as when done sequentially
+        loop {
+            let pending = next_to_query(&outcomes(&results), self.setup.standby);
+            if pending.is_empty() { break; }
+
+            // nodes without a working client fail right away, without being queried
+            let (ready, broken): (Vec<usize>, Vec<usize>) = pending.into_iter().partition(|x| clients[*x].0.is_ok());
+            for i in broken {
+                let error = clients[i].0.as_ref().err().cloned().unwrap_or_default();
+                results[i] = Some((Err(error), Duration::ZERO));
+            }
+
+            let batch: Vec<&[TraefikAPIClient]> = ready.iter()
+                .map(|x| clients[*x].0.as_deref().unwrap_or_default()).collect();
+            let fetched = fetch_routers_concurrently(&batch, &protocols,
+                self.setup.skip_unhealthy, self.setup.concurrency as usize);
+            for (i, result) in ready.into_iter().zip(fetched) {
+                results[i] = Some(result);
+            }
+        }
 
+        let covered = covered_by_standby(&outcomes(&results), self.setup.standby);
+
+        let polled = results.iter().zip(clients).filter(|(x, (y, _))| x.is_some() && y.is_ok()).count();
+        self.metrics.traefik_hosts_polled.set(polled as i64);
+
+        for (i, ((node_clients, node), result)) in self.volatile.traefik_clients.iter().zip(results).enumerate() {
+            let Some((result, duration)) = result else {
+                debug!(node = node.given_name.as_str(); "Standby node is not needed");
+                continue;
+            };
+            match node_clients.is_ok() {
+                true  => self.metrics.observe_poll(&node.given_name, result.is_ok(), duration),
+                false => self.metrics.observe_failure(&node.given_name),
+            }
+
+            let mut routers = match result {
+                Ok(routers) => routers,
+                Err(e) if covered.contains(&i) => {
+                    warn!(node = node.given_name.as_str(), error = e.as_str();
+                        "Traefik node failed, using a standby instead");
+                    continue;
+                },
+                Err(e) => {
+                    if self.setup.failure_policy == FailurePolicy::FailFast {
+                        return Err(format!("Unable to query Traefik on {}: {}", node.given_name, e).into());
+                    }
+
+                    self.volatile.failures.push(NodeFailure { node: node.given_name.clone(), error: e });
+
+                    if self.setup.failure_policy != FailurePolicy::KeepPrevious { continue; }
+
+                    previous_routers.iter()
+                        .filter(|(_, x)| x.given_name == node.given_name)
+                        .map(|(x, _)| x.clone())
+                        .collect()
+                },
+            };
+
+            // other nodes serving the same router are dealt with by the conflict policy
             let existing_routers: Vec<&TraefikRouter> = all_routers.iter()
+                .filter(|(_, x)| Rc::ptr_eq(x, node))
                 .map(|(x, _)| x)
                 .collect::<Vec<_>>();
 
@@ -162,31 +771,93 @@ impl Processing {
             }
         }
 
+        info!(routers = all_routers.len(); "Collected Traefik routers");
+        self.metrics.routers.set(all_routers.len() as i64);
         self.volatile.traefik_router = all_routers;
 
         Ok(())
     }
 
-    pub fn generate_json(&self) -> Result<(), Box<dyn std::error::Error>> {
-        #[derive(Serialize, Debug)]
-        struct HeadscaleDNSEntry {
-            name:        String, // DNS domain we're trying to resolve
-            #[serde(rename = "type")] // we can't name it "type" in rust as its a reserved keyword
-            record_type: String, // Record type (A or AAAA only, for now)
-            value:       String,
+    // Returns whether any of the outputs has changed
+    pub fn generate_json(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
+        let dns_entries = self.generate_records()?;
+
+        self.metrics.records.reset();
+        for i in &dns_entries {
+            self.metrics.records.with_label_values(&[i.record_type.as_str()]).inc();
         }
 
-        impl PartialEq for HeadscaleDNSEntry {
-            fn eq(&self, other: &Self) -> bool {
-                if (self.name == other.name) &&
-                    (self.record_type == other.record_type) {
-                    return true;
-                }
-                false
+        let written = self.setup.output.write_all(&dns_entries, self.volatile.reload_pending);
+        self.volatile.reload_pending = written.is_err();
+        let changed = written?;
+        self.metrics.mark_written();
+
+        if changed {
+            info!(records = dns_entries.len(); "DNS records have changed, outputs were updated");
+        } else {
+            debug!(records = dns_entries.len(); "DNS records are unchanged");
+        }
+
+        Ok(changed)
+    }
+
+    // Returns why a router shouldn't be used, if there's a reason not to
+    fn router_filter(&self, router: &TraefikRouter) -> Option<String> {
+        // accept both `docker` and `@docker`, the latter being how they're shown in router names
+        let has_provider = |list: &Vec<String>|
+            list.iter().any(|x| x.trim_start_matches('@') == router.provider);
+        let has_entry_point = |list: &Vec<String>|
+            router.entry_points.iter().any(|x| list.contains(x));
+
+        if !self.setup.entry_points.is_empty() && !has_entry_point(&self.setup.entry_points) {
+            return Some("not on an allowed entry point".to_string());
+        }
+        if has_entry_point(&self.setup.excluded_entry_points) {
+            return Some("on an excluded entry point".to_string());
+        }
+
+        if !self.setup.providers.is_empty() && !has_provider(&self.setup.providers) {
+            return Some("not from an allowed provider".to_string());
+        }
+        if has_provider(&self.setup.excluded_providers) {
+            return Some("from an excluded provider".to_string());
+        }
+
+        if let Some(r) = &self.router_whitelist {
+            if !r.is_match(&router.name) {
+                return Some("name not matching the whitelist".to_string());
+            }
+        }
+        if let Some(r) = &self.router_blacklist {
+            if r.is_match(&router.name) {
+                return Some("name matching the blacklist".to_string());
             }
         }
 
-        let mut dns_entries: Vec<HeadscaleDNSEntry> = Vec::new();
+        match (self.setup.tls, router.has_tls()) {
+            (TlsFilter::Required, false) => return Some("TLS is not configured".to_string()),
+            (TlsFilter::Forbidden, true) => return Some("TLS is configured".to_string()),
+            _ => (),
+        }
+
+        if self.setup.skip_disabled && !router.is_enabled() {
+            return Some(match router.error.is_empty() {
+                true  => format!("router is {}", router.status),
+                false => format!("router is {} ({})", router.status, router.error.join(", ")),
+            });
+        }
+
+        if self.setup.skip_unhealthy && router.service_up == Some(false) {
+            return Some("no servers of its service are up".to_string());
+        }
+
+        None
+    }
+
+    fn generate_records(&self) -> Result<Vec<DnsRecord>> {
+        let mut traefik_records: Vec<(DnsRecord, Rc<HeadscaleNode>)> = Vec::new();
+        // resolving a target blocks, so that's done once per node rather than once per router
+        let mut cname_targets: Vec<(Rc<HeadscaleNode>, Option<String>)> = Vec::new();
 
         for (router, client) in &self.volatile.traefik_router {
             // drop dns entries based on whether a middleware exists or not
@@ -196,7 +867,7 @@ impl Processing {
             for i in &self.setup.middlewares {
                 match router.middlewares {
                     Some(ref middlewares) => {
-                        if middlewares.contains(&i) {
+                        if middlewares.contains(i) {
                             middleware_found = true;
                             break;
                         }
@@ -207,65 +878,535 @@ impl Processing {
             }
 
             // skip if a middleware whitelist exists and a middleware has not been found
-            if !middleware_found && !self.setup.middlewares.is_empty() { continue; }
+            if !middleware_found && !self.setup.middlewares.is_empty() {
+                debug!(node = client.given_name.as_str(), rule = router.rule.as_str();
+                    "Dropping router without a whitelisted middleware");
+                continue;
+            }
+
+            if let Some(reason) = self.router_filter(router) {
+                info!(node = client.given_name.as_str(), router = router.name.as_str(), reason = reason.as_str();
+                    "Dropping router");
+                continue;
+            }
 
             // get list domains associated with each traefik router
-            let domains = router.get_domain_list();
+            let domain_list = router.get_domain_list();
+            for i in &domain_list.warnings {
+                // those are taken care of below
+                if self.setup.host_regexp_mode != HostRegexpMode::Ignore && matches!(i, RuleWarning::HostRegexp(_)) {
+                    continue;
+                }
+                info!(node = client.given_name.as_str(), rule = router.rule.as_str(), warning:% = i;
+                    "Part of a router rule was not turned into a domain");
+            }
+            let mut domains = domain_list.hosts;
+            for i in &domain_list.regexps {
+                for domain in self.expand_host_regexp(i) {
+                    if !domains.contains(&domain) { domains.push(domain); }
+                }
+            }
 
             // skip rules that do not contain a domain
             if domains.is_empty() { continue; }
 
-            for ip in &client.ip_addresses {
-                for domain in &domains {
-                    let dns_entry = HeadscaleDNSEntry {
-                        record_type: (if ip.contains(':') { "AAAA" } else { "A" }).to_string(),
-                        value: ip.clone(),
-                        name: domain.clone(),
-                    };
+            let cname_target = match cname_targets.iter().find(|(x, _)| Rc::ptr_eq(x, client)) {
+                Some((_, target)) => target.clone(),
+                None => {
+                    let target = self.cname_target(client);
+                    cname_targets.push((Rc::clone(client), target.clone()));
+                    target
+                },
+            };
 
-                    // we don't want duplicates
-                    if dns_entries.contains(&dns_entry) {
-                        continue;
-                    }
+            let mut candidates = Vec::new();
+            for domain in &domains {
+                match &cname_target {
+                    Some(target) => candidates.push(DnsRecord::new(domain, "CNAME", target)),
+                    None => for ip in client.ip_addresses.iter().filter(|x| self.setup.traefik_family.allows(x)) {
+                        candidates.push(DnsRecord::address(domain, ip));
+                    },
+                }
+            }
 
-                    match &self.domain_whitelist {
-                        Some(r) => if !r.is_match(&dns_entry.name) { continue; },
-                        None => {},
+            for dns_entry in candidates {
+                if let Some(r) = &self.domain_whitelist {
+                    if !r.is_match(&dns_entry.name) {
+                        debug!(domain = dns_entry.name.as_str(); "Dropping domain not matching the whitelist");
+                        continue;
                     }
+                }
 
-                    match &self.domain_blacklist {
-                        Some(r) => if r.is_match(&dns_entry.name) { continue; },
-                        None => {},
+                if let Some(r) = &self.domain_blacklist {
+                    if r.is_match(&dns_entry.name) {
+                        debug!(domain = dns_entry.name.as_str(); "Dropping domain matching the blacklist");
+                        continue;
                     }
+                }
 
-                    dns_entries.push(dns_entry);
+                // eg. the same domain on both an HTTP and a TCP router
+                if traefik_records.iter().any(|(x, node)| Rc::ptr_eq(node, client) && is_same_record(x, &dns_entry)) {
+                    continue;
                 }
+
+                traefik_records.push((dns_entry, Rc::clone(client)));
             }
         }
-        
+
+        let mut dns_entries = resolve_conflicts(traefik_records, self.setup.conflict_policy, &self.setup.node_priority);
+
         // subroutine that adds the magicDNS domains
         if self.setup.old_magicdns {
             for i in &self.volatile.headscale_nodes {
-                for j in &i.ip_addresses {
+                for j in i.ip_addresses.iter().filter(|x| self.setup.magicdns_family.allows(x)) {
                     for k in i.get_magic_dns_domains(&self.headscale_client) {
-                        dns_entries.push(HeadscaleDNSEntry{
-                            name: k,
-                            record_type: if j.contains(':') { "AAAA".to_string() } else { "A".to_string() },
-                            value: j.clone(),
-                        });
+                        dns_entries.push(DnsRecord::address(&k, j));
                     }
                 }
             }
         }
 
-        let file = File::create(&self.setup.output_path)
-            .context(r#"Unable to write to the output file.
-Make sure that the output path is correct!"#)?;
+        match &self.setup.static_records {
+            Some(path) => Ok(merge_static_records(dns_entries, read_static_records(path)?,
+                self.setup.static_precedence)),
+            None => Ok(dns_entries),
+        }
+    }
 
-        let writer = BufWriter::new(file);
+    // What the node's domains should be a CNAME of, if they should be one at all
+    fn cname_target(&self, node: &HeadscaleNode) -> Option<String> {
+        if self.setup.record_mode != RecordMode::Cname { return None; }
 
-        serde_json::to_writer_pretty(writer, &dns_entries)?;
+        let target = node.get_magic_dns_fqdn(&self.headscale_client)?;
+        if self.setup.cname_verify && (target.as_str(), 0).to_socket_addrs().is_err() {
+            info!(node = node.given_name.as_str(), target = target.as_str();
+                "CNAME target does not resolve, using address records instead");
+            return None;
+        }
 
-        Ok(())
+        Some(target)
+    }
+
+    // The domains a HostRegexp argument stands for, depending on the mode
+    fn expand_host_regexp(&self, source: &str) -> Vec<String> {
+        match self.setup.host_regexp_mode {
+            HostRegexpMode::Ignore => Vec::new(),
+            HostRegexpMode::Expand => match rule::host_regexp(source) {
+                Ok(r) => {
+                    let matched: Vec<String> = self.setup.host_regexp_candidates.iter()
+                        .filter(|x| r.is_match(x)).cloned().collect();
+                    if matched.is_empty() {
+                        info!(regexp = source; "No host name candidates match the host regex");
+                    }
+                    matched
+                },
+                Err(e) => {
+                    warn!(regexp = source, error:% = e; "Unable to use the host regex");
+                    Vec::new()
+                },
+            },
+            HostRegexpMode::Wildcard => match rule::host_regexp_wildcard(source) {
+                Some(x) => vec![x],
+                None => {
+                    info!(regexp = source; "The host regex cannot be turned into a wildcard domain");
+                    Vec::new()
+                },
+            },
+        }
+    }
+
+}
+
+// Picks which node's records to keep for every domain served by more than one node
+fn resolve_conflicts(records: Vec<(DnsRecord, Rc<HeadscaleNode>)>, policy: ConflictPolicy, priority: &[String])
+        -> Vec<DnsRecord> {
+    let mut names: Vec<&str> = Vec::new();
+    for (record, _) in &records {
+        if !names.contains(&record.name.as_str()) { names.push(record.name.as_str()); }
+    }
+
+    let mut resolved: Vec<DnsRecord> = Vec::new();
+    for name in names {
+        let of_name: Vec<&(DnsRecord, Rc<HeadscaleNode>)> = records.iter()
+            .filter(|(x, _)| x.name == name).collect();
+
+        // in the order they were seen in
+        let mut nodes: Vec<&Rc<HeadscaleNode>> = Vec::new();
+        for (_, node) in &of_name {
+            if !nodes.iter().any(|x| Rc::ptr_eq(x, node)) { nodes.push(node); }
+        }
+
+        let names = |x: &[&Rc<HeadscaleNode>]| x.iter().map(|x| x.given_name.as_str()).collect::<Vec<_>>().join(",");
+        let round_robin = policy == ConflictPolicy::All && of_name.iter().all(|(x, _)| x.is_address());
+        if nodes.len() > 1 && policy == ConflictPolicy::All && !round_robin {
+            warn!(domain = name, nodes = names(&nodes).as_str();
+                "A CNAME can't be combined with other records, keeping only the first seen node's");
+        }
+
+        let winners: Vec<&Rc<HeadscaleNode>> = match nodes.len() == 1 || round_robin {
+            true  => nodes.clone(),
+            false => vec![pick_node(&nodes, policy, priority)],
+        };
+
+        if nodes.len() > 1 {
+            info!(domain = name, nodes = names(&nodes).as_str(), kept = names(&winners).as_str(),
+                policy:? = policy; "Several nodes serve the same domain");
+        }
+
+        for (record, _) in of_name.iter().filter(|(_, node)| winners.iter().any(|x| Rc::ptr_eq(x, node))) {
+            // a CNAME can't sit next to anything else, nor can the same record be there twice
+            let clashes = resolved.iter().any(|x| x == record
+                && (x.record_type == "CNAME" || record.record_type == "CNAME" || x.value == record.value));
+            if !clashes {
+                resolved.push(record.clone());
+            }
+        }
+    }
+
+    resolved
+}
+
+fn pick_node<'a>(nodes: &[&'a Rc<HeadscaleNode>], policy: ConflictPolicy, priority: &[String])
+        -> &'a Rc<HeadscaleNode> {
+    // min_by_key keeps the first one on ties, so the first seen wins those
+    let picked = match policy {
+        ConflictPolicy::FirstSeen | ConflictPolicy::All => nodes.first(),
+        ConflictPolicy::Priority => nodes.iter().min_by_key(|x|
+            priority.iter().position(|y| *y == x.given_name).unwrap_or(usize::MAX)),
+        ConflictPolicy::Recent => nodes.iter().min_by_key(|x| Reverse((x.online, x.get_last_seen()))),
+    };
+
+    picked.expect("conflicts involve at least two nodes")
+}
+
+fn merge_static_records(generated: Vec<DnsRecord>, static_records: Vec<DnsRecord>, precedence: StaticPrecedence)
+        -> Vec<DnsRecord> {
+    let mut records: Vec<DnsRecord> = Vec::new();
+
+    // several addresses of the same type are fine here, as they were written by hand
+    for record in static_records {
+        let duplicate = records.iter().any(|x| x == &record
+            && !(x.is_address() && x.record_type == record.record_type && x.value != record.value));
+        if duplicate {
+            warn!(domain = record.name.as_str(), record_type = record.record_type.as_str(),
+                value = record.value.as_str(); "Ignoring a duplicate static record");
+            continue;
+        }
+        records.push(record);
+    }
+
+    let (winners, losers) = match precedence {
+        StaticPrecedence::Static    => (records, generated),
+        StaticPrecedence::Generated => (generated, records),
+    };
+
+    // a name is owned entirely by one side, otherwise eg. a static A record
+    // would still leave the generated AAAA record pointing somewhere else
+    let mut merged = winners.clone();
+    for record in losers {
+        match winners.iter().find(|x| x.name == record.name) {
+            // the same record on both sides isn't worth mentioning
+            Some(_) if winners.iter().any(|x| is_same_record(x, &record)) => {},
+            Some(x) => info!(domain = record.name.as_str(),
+                kept = format!("{} {}", x.record_type, x.value).as_str(),
+                dropped = format!("{} {}", record.record_type, record.value).as_str(),
+                precedence:? = precedence; "Static and generated records conflict"),
+            None => merged.push(record),
+        }
+    }
+
+    merged
+}
+
+// Headscale always reports tags with their prefix, which is easy to forget in the config
+fn normalize_tag(tag: &str) -> String {
+    match tag.starts_with("tag:") {
+        true  => tag.to_string(),
+        false => format!("tag:{}", tag),
+    }
+}
+
+// Settings holding a whole map, given either as the path to a JSON file or as JSON
+// (which is also how a table in the config file arrives here)
+fn read_json_setting<T: DeserializeOwned + Default>(value: Option<&str>, what: &str, allow_world_readable: bool)
+        -> Result<T> {
+    match value {
+        Some(json) if json.trim_start().starts_with('{') => serde_json::from_str(json)
+            .with_context(|| format!("The {} are invalid", what)),
+        Some(path) => {
+            let content = fs::read_to_string(path)
+                .with_context(|| format!(r#"Unable to read the {} "{}""#, what, path))?;
+            let parsed: T = serde_json::from_str(&content)
+                .with_context(|| format!(r#"The {} "{}" are invalid"#, what, path))?;
+
+            // the file is as much of a secret as the passwords in it
+            if serde_json::from_str(&content).is_ok_and(|x| has_password(&x)) {
+                secret::check_permissions(Path::new(path), allow_world_readable)?;
+            }
+
+            Ok(parsed)
+        },
+        None => Ok(T::default()),
+    }
+}
+
+fn has_password(value: &serde_json::Value) -> bool {
+    match value {
+        serde_json::Value::Object(x) => x.contains_key("password") || x.values().any(has_password),
+        _ => false,
+    }
+}
+
+fn is_same_record(a: &DnsRecord, b: &DnsRecord) -> bool {
+    a.name == b.name && a.record_type == b.record_type && a.value == b.value
+}
+
+// Tries each of the node's addresses until one of them can be connected to
+fn fetch_routers(clients: &[TraefikAPIClient], protocols: &[TraefikProtocol], check_services: bool)
+        -> Result<Vec<TraefikRouter>, String> {
+    let mut last_error = String::from("The node has no usable addresses");
+
+    for (i, client) in clients.iter().enumerate() {
+        match fetch_routers_from(client, protocols, check_services) {
+            Ok(routers) => return Ok(routers),
+            Err(e) if is_connection_error(e.as_ref()) && i + 1 < clients.len() => {
+                // the top level message alone can't tell a refused connection from a timeout
+                last_error = describe(e.as_ref());
+                debug!(error = last_error.as_str(); "Unable to connect to Traefik, trying the next address");
+            },
+            Err(e) => return Err(describe(e.as_ref())),
+        }
+    }
+
+    Err(last_error)
+}
+
+fn fetch_routers_from(client: &TraefikAPIClient, protocols: &[TraefikProtocol], check_services: bool)
+        -> Result<Vec<TraefikRouter>, Box<dyn std::error::Error>> {
+    let mut routers = Vec::new();
+    for protocol in protocols {
+        routers.append(&mut TraefikAPIClient::get_router_list(client, *protocol)?);
+
+        if check_services {
+            TraefikAPIClient::check_services(client, *protocol, &mut routers)?;
+        }
+    }
+    Ok(routers)
+}
+
+// Whether no response came back at all (refused, reset, timed out...), anything else
+// (eg. wrong credentials) would fail the same way on every address
+fn is_connection_error(e: &(dyn std::error::Error + 'static)) -> bool {
+    e.downcast_ref::<reqwest::Error>().is_some_and(|x| x.is_connect() || x.is_timeout() || x.is_request())
+}
+
+// A client per address of a node, or why they couldn't be set up
+type NodeClients = Result<Vec<TraefikAPIClient>, String>;
+
+// The routers of a node (or why it couldn't be queried), along with how long it took
+// Which nodes to query next, given whether each one worked out so far (None if it hasn't been
+// queried). In standby mode, only the first node (the first preferred one, as they're sorted
+// first) is queried, the others are standbys that are queried one by one if the ones before fail.
+// Otherwise, they're all queried at once.
+fn next_to_query(outcomes: &[Option<bool>], standby: bool) -> Vec<usize> {
+    let last = outcomes.iter().rposition(|x| x.is_some());
+    match (standby, last) {
+        (false, None) => (0..outcomes.len()).collect(),
+        (true, None) => (0..outcomes.len().min(1)).collect(),
+        (true, Some(last)) if outcomes[last] == Some(false) && last + 1 < outcomes.len() => vec![last + 1],
+        _ => Vec::new(),
+    }
+}
+
+// The failures that don't count, as one of their standbys worked out
+fn covered_by_standby(outcomes: &[Option<bool>], standby: bool) -> Vec<usize> {
+    match outcomes.iter().position(|x| *x == Some(true)) {
+        Some(working) if standby => (0..working).collect(),
+        _ => Vec::new(),
+    }
+}
+
+type FetchResult = (Result<Vec<TraefikRouter>, String>, Duration);
+
+// Spreads the clients across at most `concurrency` worker threads, the results
+// (along with how long they took) are returned in the same order as the clients
+fn fetch_routers_concurrently(clients: &[&[TraefikAPIClient]], protocols: &[TraefikProtocol],
+        check_services: bool, concurrency: usize) -> Vec<FetchResult> {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new((0..clients.len()).map(|_| None).collect::<Vec<_>>());
+
+    thread::scope(|s| {
+        for _ in 0..concurrency.min(clients.len()) {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if i >= clients.len() { break; }
+
+                let start = Instant::now();
+                let result = fetch_routers(clients[i], protocols, check_services);
+                results.lock().unwrap()[i] = Some((result, start.elapsed()));
+            });
+        }
+    });
+
+    results.into_inner().unwrap().into_iter()
+        .map(|x| x.expect("every client is queried exactly once"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(records: &[DnsRecord]) -> Vec<String> {
+        records.iter().map(|x| format!("{} {} {}", x.name, x.record_type, x.value)).collect()
+    }
+
+    fn node(name: &str, online: bool, last_seen: &str) -> Rc<HeadscaleNode> {
+        let mut node = HeadscaleNode::external(name, &[]);
+        node.external = false;
+        node.online = online;
+        node.last_seen = Some(last_seen.to_string());
+        Rc::new(node)
+    }
+
+    // Both nodes serve app.example.com, the first one is seen first but is offline
+    fn conflicting(second: &str) -> Vec<(DnsRecord, Rc<HeadscaleNode>)> {
+        let first = node("edge1", false, "2026-10-17T12:00:00Z");
+        let second = node(second, true, "2026-10-17T11:00:00Z");
+        vec![
+            (DnsRecord::new("app.example.com", "A", "100.64.0.1"), first.clone()),
+            (DnsRecord::new("app.example.com", "AAAA", "fd7a::1"), first.clone()),
+            (DnsRecord::new("only.example.com", "A", "100.64.0.1"), first),
+            (DnsRecord::new("app.example.com", "A", "100.64.0.2"), second.clone()),
+            (DnsRecord::new("app.example.com", "AAAA", "fd7a::2"), second),
+        ]
+    }
+
+    #[test]
+    fn conflict_policies() {
+        let first = vec!["app.example.com A 100.64.0.1", "app.example.com AAAA fd7a::1", "only.example.com A 100.64.0.1"];
+        let second = vec!["app.example.com A 100.64.0.2", "app.example.com AAAA fd7a::2", "only.example.com A 100.64.0.1"];
+
+        assert_eq!(values(&resolve_conflicts(conflicting("edge2"), ConflictPolicy::FirstSeen, &[])), first);
+
+        let priority = ["edge2".to_string(), "edge1".to_string()];
+        assert_eq!(values(&resolve_conflicts(conflicting("edge2"), ConflictPolicy::Priority, &priority)), second);
+        // unlisted nodes come last, ties go to the first seen
+        let priority = ["edge1".to_string()];
+        assert_eq!(values(&resolve_conflicts(conflicting("edge2"), ConflictPolicy::Priority, &priority)), first);
+        assert_eq!(values(&resolve_conflicts(conflicting("edge2"), ConflictPolicy::Priority, &[])), first);
+
+        // online beats seen more recently
+        assert_eq!(values(&resolve_conflicts(conflicting("edge2"), ConflictPolicy::Recent, &[])), second);
+
+        assert_eq!(values(&resolve_conflicts(conflicting("edge2"), ConflictPolicy::All, &[])), vec![
+            "app.example.com A 100.64.0.1",
+            "app.example.com AAAA fd7a::1",
+            "app.example.com A 100.64.0.2",
+            "app.example.com AAAA fd7a::2",
+            "only.example.com A 100.64.0.1",
+        ]);
+    }
+
+    #[test]
+    fn conflicting_cnames() {
+        let first = node("edge1", false, "2026-10-17T12:00:00Z");
+        let second = node("edge2", true, "2026-10-17T11:00:00Z");
+        let records = || vec![
+            (DnsRecord::new("app.example.com", "CNAME", "edge1.example.com"), first.clone()),
+            (DnsRecord::new("app.example.com", "A", "100.64.0.2"), second.clone()),
+        ];
+
+        assert_eq!(values(&resolve_conflicts(records(), ConflictPolicy::FirstSeen, &[])),
+            vec!["app.example.com CNAME edge1.example.com"]);
+        assert_eq!(values(&resolve_conflicts(records(), ConflictPolicy::Recent, &[])),
+            vec!["app.example.com A 100.64.0.2"]);
+        // nothing can be combined with a CNAME, so all falls back to the first seen node
+        assert_eq!(values(&resolve_conflicts(records(), ConflictPolicy::All, &[])),
+            vec!["app.example.com CNAME edge1.example.com"]);
+    }
+
+    // Queries the way update_routers does, where `works` is the outcome of each node
+    fn standby_chain(works: &[bool], standby: bool) -> (Vec<Option<bool>>, Vec<usize>) {
+        let mut outcomes: Vec<Option<bool>> = vec![None; works.len()];
+        loop {
+            let pending = next_to_query(&outcomes, standby);
+            if pending.is_empty() { break; }
+            for i in pending {
+                outcomes[i] = Some(works[i]);
+            }
+        }
+
+        let covered = covered_by_standby(&outcomes, standby);
+        (outcomes, covered)
+    }
+
+    #[test]
+    fn standby_nodes() {
+        // without standby mode everything is queried and every failure counts
+        assert_eq!(standby_chain(&[false, true, true], false), (vec![Some(false), Some(true), Some(true)], vec![]));
+        assert_eq!(standby_chain(&[], true), (vec![], vec![]));
+
+        // the primary works out, the standbys aren't needed
+        assert_eq!(standby_chain(&[true, true, true], true), (vec![Some(true), None, None], vec![]));
+
+        // the primary fails and its standby takes over
+        assert_eq!(standby_chain(&[false, true, true], true), (vec![Some(false), Some(true), None], vec![0]));
+
+        // everything fails, so every failure counts
+        assert_eq!(standby_chain(&[false, false, false], true),
+            (vec![Some(false), Some(false), Some(false)], vec![]));
+
+        // a node whose client couldn't be set up fails like any other, without stopping the chain
+        assert_eq!(standby_chain(&[false, false, true, true], true),
+            (vec![Some(false), Some(false), Some(true), None], vec![0, 1]));
+    }
+
+    #[test]
+    fn static_records_precedence() {
+        let generated = || vec![
+            DnsRecord::new("app.example.com", "A", "100.64.0.1"),
+            DnsRecord::new("app.example.com", "AAAA", "fd7a::1"),
+            DnsRecord::new("nas.example.com", "A", "100.64.0.1"),
+        ];
+        let static_records = || vec![
+            DnsRecord::new("app.example.com", "A", "192.168.1.5"),
+            DnsRecord::new("nas.example.com", "A", "100.64.0.1"),
+            DnsRecord::new("printer.example.com", "A", "192.168.1.6"),
+        ];
+
+        // the static A record takes the name over, the generated AAAA record goes along with it
+        assert_eq!(values(&merge_static_records(generated(), static_records(), StaticPrecedence::Static)), vec![
+            "app.example.com A 192.168.1.5",
+            "nas.example.com A 100.64.0.1",
+            "printer.example.com A 192.168.1.6",
+        ]);
+
+        assert_eq!(values(&merge_static_records(generated(), static_records(), StaticPrecedence::Generated)), vec![
+            "app.example.com A 100.64.0.1",
+            "app.example.com AAAA fd7a::1",
+            "nas.example.com A 100.64.0.1",
+            "printer.example.com A 192.168.1.6",
+        ]);
+    }
+
+    #[test]
+    fn duplicate_static_records() {
+        let static_records = vec![
+            DnsRecord::new("nas.example.com", "A", "192.168.1.5"),
+            // several addresses are fine, the same one twice isn't
+            DnsRecord::new("nas.example.com", "A", "192.168.1.6"),
+            DnsRecord::new("nas.example.com", "A", "192.168.1.5"),
+            // nor is a CNAME next to them
+            DnsRecord::new("nas.example.com", "CNAME", "nas.lan"),
+            DnsRecord::new("www.example.com", "CNAME", "nas.example.com"),
+            DnsRecord::new("www.example.com", "A", "192.168.1.5"),
+        ];
+
+        assert_eq!(values(&merge_static_records(Vec::new(), static_records, StaticPrecedence::Static)), vec![
+            "nas.example.com A 192.168.1.5",
+            "nas.example.com A 192.168.1.6",
+            "www.example.com CNAME nas.example.com",
+        ]);
     }
 }
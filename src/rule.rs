@@ -0,0 +1,521 @@
+use regex::Regex;
+use thiserror::Error;
+
+// Parser for Traefik's router rule language, eg.
+// (Host(`a.example.com`) || Host(`b.example.com`)) && !PathPrefix(`/admin`)
+// See: https://doc.traefik.io/traefik/routing/routers/#rule
+
+#[derive(Debug, Error, PartialEq, Clone)]
+pub enum RuleError {
+    #[error("Unexpected character '{0}' at position {1}")]
+    UnexpectedChar(char, usize),
+    #[error("Unterminated string starting at position {0}")]
+    UnterminatedString(usize),
+    #[error("Unexpected token {0} at position {1}")]
+    UnexpectedToken(String, usize),
+    #[error("Unexpected end of the rule")]
+    UnexpectedEnd,
+}
+
+// Things that can match a host, but that we can't turn into a list of domains
+#[derive(Debug, Error, PartialEq, Clone)]
+pub enum RuleWarning {
+    #[error("Host regex `{0}` cannot be enumerated")]
+    HostRegexp(String),
+    #[error("Negated host `{0}` cannot be enumerated")]
+    NegatedHost(String),
+    #[error("Catch-all SNI matcher `*` cannot be enumerated")]
+    CatchAll,
+    #[error("Unknown matcher `{0}`")]
+    UnknownMatcher(String),
+    #[error("Rule could not be parsed: {0}")]
+    Syntax(RuleError),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Token {
+    Ident(String),
+    Str(String),
+    LParen,
+    RParen,
+    Comma,
+    And,
+    Or,
+    Not,
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Ident(x) => write!(f, "`{}`", x),
+            Token::Str(x)   => write!(f, "\"{}\"", x),
+            Token::LParen   => write!(f, "'('"),
+            Token::RParen   => write!(f, "')'"),
+            Token::Comma    => write!(f, "','"),
+            Token::And      => write!(f, "'&&'"),
+            Token::Or       => write!(f, "'||'"),
+            Token::Not      => write!(f, "'!'"),
+        }
+    }
+}
+
+fn tokenize(rule: &str) -> Result<Vec<(Token, usize)>, RuleError> {
+    let chars: Vec<char> = rule.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+
+        match c {
+            _ if c.is_whitespace() => { i += 1; continue; },
+            '(' => { tokens.push((Token::LParen, start)); i += 1; },
+            ')' => { tokens.push((Token::RParen, start)); i += 1; },
+            ',' => { tokens.push((Token::Comma, start)); i += 1; },
+            '!' => { tokens.push((Token::Not, start)); i += 1; },
+            '&' | '|' => {
+                if chars.get(i + 1) != Some(&c) {
+                    return Err(RuleError::UnexpectedChar(c, i));
+                }
+                tokens.push((if c == '&' { Token::And } else { Token::Or }, start));
+                i += 2;
+            },
+            // raw strings, no escapes whatsoever
+            '`' => {
+                let end = chars[i + 1..].iter().position(|&x| x == '`')
+                    .ok_or(RuleError::UnterminatedString(start))?;
+                tokens.push((Token::Str(chars[i + 1..i + 1 + end].iter().collect()), start));
+                i += end + 2;
+            },
+            // interpreted strings, only the escapes that make sense in a hostname or a path
+            '"' => {
+                let mut value = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => return Err(RuleError::UnterminatedString(start)),
+                        Some('"') => break,
+                        Some('\\') => {
+                            match chars.get(i + 1) {
+                                Some(&x) => value.push(x),
+                                None => return Err(RuleError::UnterminatedString(start)),
+                            }
+                            i += 2;
+                        },
+                        Some(&x) => { value.push(x); i += 1; },
+                    }
+                }
+                tokens.push((Token::Str(value), start));
+                i += 1;
+            },
+            _ if c.is_ascii_alphabetic() => {
+                while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                tokens.push((Token::Ident(chars[start..i].iter().collect()), start));
+            },
+            _ => return Err(RuleError::UnexpectedChar(c, i)),
+        }
+    }
+
+    Ok(tokens)
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum RuleExpr {
+    Matcher { name: String, args: Vec<String> },
+    And(Box<RuleExpr>, Box<RuleExpr>),
+    Or(Box<RuleExpr>, Box<RuleExpr>),
+    Not(Box<RuleExpr>),
+}
+
+// Plain recursive descent, precedence being: ! > && > ||
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(x, _)| x)
+    }
+
+    fn next(&mut self) -> Result<(Token, usize), RuleError> {
+        let token = self.tokens.get(self.pos).cloned().ok_or(RuleError::UnexpectedEnd)?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), RuleError> {
+        let (token, pos) = self.next()?;
+        if token != expected {
+            return Err(RuleError::UnexpectedToken(token.to_string(), pos));
+        }
+        Ok(())
+    }
+
+    fn parse_or(&mut self) -> Result<RuleExpr, RuleError> {
+        let mut left = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            left = RuleExpr::Or(Box::new(left), Box::new(self.parse_and()?));
+        }
+        Ok(left)
+    }
+
+    fn parse_and(&mut self) -> Result<RuleExpr, RuleError> {
+        let mut left = self.parse_unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            left = RuleExpr::And(Box::new(left), Box::new(self.parse_unary()?));
+        }
+        Ok(left)
+    }
+
+    fn parse_unary(&mut self) -> Result<RuleExpr, RuleError> {
+        match self.next()? {
+            (Token::Not, _) => Ok(RuleExpr::Not(Box::new(self.parse_unary()?))),
+            (Token::LParen, _) => {
+                let expr = self.parse_or()?;
+                self.expect(Token::RParen)?;
+                Ok(expr)
+            },
+            (Token::Ident(name), _) => {
+                self.expect(Token::LParen)?;
+                let mut args = Vec::new();
+                if self.peek() == Some(&Token::RParen) {
+                    self.pos += 1;
+                    return Ok(RuleExpr::Matcher { name, args });
+                }
+                loop {
+                    match self.next()? {
+                        (Token::Str(x), _) => args.push(x),
+                        (token, pos) => return Err(RuleError::UnexpectedToken(token.to_string(), pos)),
+                    }
+                    match self.next()? {
+                        (Token::Comma, _) => continue,
+                        (Token::RParen, _) => break,
+                        (token, pos) => return Err(RuleError::UnexpectedToken(token.to_string(), pos)),
+                    }
+                }
+                Ok(RuleExpr::Matcher { name, args })
+            },
+            (token, pos) => Err(RuleError::UnexpectedToken(token.to_string(), pos)),
+        }
+    }
+}
+
+pub fn parse(rule: &str) -> Result<RuleExpr, RuleError> {
+    let mut parser = Parser { tokens: tokenize(rule)?, pos: 0 };
+    let expr = parser.parse_or()?;
+
+    if let Some((token, pos)) = parser.tokens.get(parser.pos) {
+        return Err(RuleError::UnexpectedToken(token.to_string(), *pos));
+    }
+
+    Ok(expr)
+}
+
+// Matchers that have nothing to do with the host, so they don't affect the domain list
+const NON_HOST_MATCHERS: &[&str] = &[
+    "path", "pathprefix", "pathregexp", "method", "header", "headerregexp", "headers",
+    "headersregexp", "query", "queryregexp", "clientip", "alpn",
+];
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct RuleHosts {
+    // Every host that the rule could possibly match, lowercased and deduplicated
+    pub hosts: Vec<String>,
+    // HostRegexp arguments as written in the rule, see host_regexp and host_regexp_wildcard
+    pub regexps: Vec<String>,
+    pub warnings: Vec<RuleWarning>,
+}
+
+impl RuleHosts {
+    fn add_host(&mut self, host: &str) {
+        let host = host.to_lowercase();
+        if !self.hosts.contains(&host) {
+            self.hosts.push(host);
+        }
+    }
+
+    fn walk(&mut self, expr: &RuleExpr, negated: bool) {
+        match expr {
+            // Either side of an AND/OR can hold the host, so we take the union of both
+            RuleExpr::And(a, b) | RuleExpr::Or(a, b) => {
+                self.walk(a, negated);
+                self.walk(b, negated);
+            },
+            RuleExpr::Not(x) => self.walk(x, !negated),
+            RuleExpr::Matcher { name, args } => {
+                match name.to_lowercase().as_str() {
+                    "host" | "hostheader" | "hostsni" => {
+                        for arg in args {
+                            if negated {
+                                self.warnings.push(RuleWarning::NegatedHost(arg.clone()));
+                            } else if arg == "*" {
+                                self.warnings.push(RuleWarning::CatchAll);
+                            } else {
+                                self.add_host(arg);
+                            }
+                        }
+                    },
+                    "hostregexp" | "hostsniregexp" => {
+                        for arg in args {
+                            if !negated && !self.regexps.contains(arg) {
+                                self.regexps.push(arg.clone());
+                            }
+                            self.warnings.push(RuleWarning::HostRegexp(arg.clone()));
+                        }
+                    },
+                    x if NON_HOST_MATCHERS.contains(&x) => {},
+                    _ => self.warnings.push(RuleWarning::UnknownMatcher(name.clone())),
+                }
+            },
+        }
+    }
+}
+
+pub fn get_hosts(rule: &str) -> RuleHosts {
+    let mut result = RuleHosts::default();
+
+    match parse(rule) {
+        Ok(expr) => result.walk(&expr, false),
+        Err(e) => result.warnings.push(RuleWarning::Syntax(e)),
+    }
+
+    result
+}
+
+// Splits a HostRegexp argument into literal text and Traefik v2 style placeholders,
+// eg. `{sub:[a-z]+}.apps.example.com`. Returns None if there are no placeholders,
+// in which case it's a plain (Traefik v3 style) regex.
+fn split_placeholders(source: &str) -> Option<Vec<(bool, String)>> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut parts: Vec<(bool, String)> = Vec::new();
+    let mut literal = String::new();
+    let mut found = false;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let starts_placeholder = chars[i] == '{'
+            && chars.get(i + 1).is_some_and(|x| x.is_ascii_alphabetic() || *x == '_');
+        if !starts_placeholder {
+            literal.push(chars[i]);
+            i += 1;
+            continue;
+        }
+
+        // the pattern itself may contain braces, eg. `{sub:[a-z]{3}}`
+        let mut depth = 0;
+        let mut end = None;
+        for (j, c) in chars.iter().enumerate().skip(i) {
+            match c {
+                '{' => depth += 1,
+                '}' => { depth -= 1; if depth == 0 { end = Some(j); break; } },
+                _ => {},
+            }
+        }
+        let end = end?;
+
+        let inner: String = chars[i + 1..end].iter().collect();
+        let pattern = match inner.split_once(':') {
+            Some((_, pattern)) => pattern.to_string(),
+            None => "[^.]+".to_string(),
+        };
+
+        if !literal.is_empty() { parts.push((false, std::mem::take(&mut literal))); }
+        parts.push((true, pattern));
+        found = true;
+        i = end + 1;
+    }
+
+    if !literal.is_empty() { parts.push((false, literal)); }
+    found.then_some(parts)
+}
+
+// Turns a HostRegexp argument into a regex matching whole (lowercase) host names
+pub fn host_regexp(source: &str) -> Result<Regex, regex::Error> {
+    let pattern = match split_placeholders(source) {
+        Some(parts) => parts.iter()
+            .map(|(placeholder, x)| match placeholder {
+                true  => format!("(?:{})", x),
+                false => regex::escape(x),
+            })
+            .collect(),
+        None => source.trim_start_matches('^').trim_end_matches('$').to_string(),
+    };
+
+    Regex::new(&format!("(?i)^(?:{})$", pattern))
+}
+
+// The wildcard domain (eg. `*.apps.example.com`) covering a HostRegexp argument,
+// if it only varies in its leftmost part(s) and the rest is a plain domain
+pub fn host_regexp_wildcard(source: &str) -> Option<String> {
+    let is_domain = |x: &str| !x.is_empty()
+        && x.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+
+    let suffix = match split_placeholders(source) {
+        Some(parts) => match parts.as_slice() {
+            [(true, _), (false, rest)] => rest.strip_prefix('.')?.to_string(),
+            _ => return None,
+        },
+        None => {
+            let source = source.trim_start_matches('^').trim_end_matches('$');
+            let (prefix, rest) = source.split_once(r"\.")?;
+            // a literal first label isn't a wildcard at all
+            if prefix.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') { return None; }
+            // everything after the first dot has to be literal, ie. only escaped dots
+            let rest = rest.replace(r"\.", ".");
+            if rest.contains('\\') { return None; }
+            rest
+        },
+    };
+
+    is_domain(&suffix).then(|| format!("*.{}", suffix.to_lowercase()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hosts(rule: &str) -> Vec<String> {
+        get_hosts(rule).hosts
+    }
+
+    #[test]
+    fn single_host() {
+        assert_eq!(hosts("Host(`example.com`)"), vec!["example.com"]);
+    }
+
+    #[test]
+    fn hyphens_and_uppercase() {
+        assert_eq!(hosts("Host(`My-App.Example.com`)"), vec!["my-app.example.com"]);
+    }
+
+    #[test]
+    fn double_quotes() {
+        assert_eq!(hosts(r#"Host("a.example.com")"#), vec!["a.example.com"]);
+        assert_eq!(hosts(r#"Host("a\".com")"#), vec!["a\".com"]);
+    }
+
+    #[test]
+    fn v2_multiple_arguments() {
+        assert_eq!(hosts("Host(`a.example.com`, `b.example.com`)"),
+            vec!["a.example.com", "b.example.com"]);
+    }
+
+    #[test]
+    fn or_of_hosts() {
+        assert_eq!(hosts("Host(`a.example.com`) || Host(`b.example.com`)"),
+            vec!["a.example.com", "b.example.com"]);
+    }
+
+    #[test]
+    fn docker_label_style() {
+        let result = get_hosts(
+            "(Host(`cloud.example.com`) || Host(`nextcloud.example.com`)) && \
+             PathPrefix(`/`) && !PathPrefix(`/admin`)");
+        assert_eq!(result.hosts, vec!["cloud.example.com", "nextcloud.example.com"]);
+        assert!(result.warnings.is_empty());
+    }
+
+    #[test]
+    fn api_dashboard_has_no_hosts() {
+        let result = get_hosts("PathPrefix(`/api`) || PathPrefix(`/dashboard`)");
+        assert!(result.hosts.is_empty());
+        assert!(result.warnings.is_empty());
+    }
+
+    #[test]
+    fn duplicates_are_dropped() {
+        assert_eq!(hosts("Host(`a.com`) || (Host(`A.com`) && Method(`GET`))"), vec!["a.com"]);
+    }
+
+    #[test]
+    fn host_regexp_is_a_warning() {
+        let result = get_hosts("HostRegexp(`{sub:[a-z]+}.apps.example.com`) || Host(`apps.example.com`)");
+        assert_eq!(result.hosts, vec!["apps.example.com"]);
+        assert_eq!(result.warnings,
+            vec![RuleWarning::HostRegexp("{sub:[a-z]+}.apps.example.com".to_string())]);
+        assert_eq!(result.regexps, vec!["{sub:[a-z]+}.apps.example.com"]);
+
+        let result = get_hosts("Host(`a.com`) && !HostRegexp(`.+\\.a\\.com`)");
+        assert!(result.regexps.is_empty());
+    }
+
+    #[test]
+    fn host_regexp_matching() {
+        let v2 = host_regexp("{sub:[a-z]{3}}.apps.example.com").unwrap();
+        assert!(v2.is_match("foo.apps.example.com"));
+        assert!(v2.is_match("FOO.apps.example.com"));
+        assert!(!v2.is_match("fooo.apps.example.com"));
+        assert!(!v2.is_match("fooxappsxexample.com"));
+
+        let v2_unnamed = host_regexp("{sub}.example.com").unwrap();
+        assert!(v2_unnamed.is_match("a.example.com"));
+        assert!(!v2_unnamed.is_match("a.b.example.com"));
+
+        let v3 = host_regexp(r"^[a-z]+\.apps\.example\.com$").unwrap();
+        assert!(v3.is_match("foo.apps.example.com"));
+        assert!(!v3.is_match("foo.apps.example.com.evil.com"));
+    }
+
+    #[test]
+    fn host_regexp_wildcards() {
+        assert_eq!(host_regexp_wildcard("{sub:[a-z]+}.Apps.example.com").as_deref(), Some("*.apps.example.com"));
+        assert_eq!(host_regexp_wildcard(r"^.+\.apps\.example\.com$").as_deref(), Some("*.apps.example.com"));
+        assert_eq!(host_regexp_wildcard("app-{n:[0-9]+}.example.com"), None);
+        assert_eq!(host_regexp_wildcard(r"^.+\.(a|b)\.example\.com$"), None);
+        assert_eq!(host_regexp_wildcard(r"^example\.com$"), None);
+    }
+
+    #[test]
+    fn negated_host_is_a_warning() {
+        let result = get_hosts("!Host(`a.com`) && !!Host(`b.com`)");
+        assert_eq!(result.hosts, vec!["b.com"]);
+        assert_eq!(result.warnings, vec![RuleWarning::NegatedHost("a.com".to_string())]);
+    }
+
+    #[test]
+    fn host_sni() {
+        assert_eq!(hosts("HostSNI(`db.example.com`)"), vec!["db.example.com"]);
+
+        let result = get_hosts("HostSNI(`*`)");
+        assert!(result.hosts.is_empty());
+        assert_eq!(result.warnings, vec![RuleWarning::CatchAll]);
+    }
+
+    #[test]
+    fn unknown_matcher() {
+        let result = get_hosts("Host(`a.com`) && Foo(`bar`)");
+        assert_eq!(result.hosts, vec!["a.com"]);
+        assert_eq!(result.warnings, vec![RuleWarning::UnknownMatcher("Foo".to_string())]);
+    }
+
+    #[test]
+    fn precedence() {
+        let expr = parse("Host(`a`) || Host(`b`) && !Path(`/`)").unwrap();
+        let matcher = |name: &str, arg: &str| Box::new(RuleExpr::Matcher {
+            name: name.to_string(), args: vec![arg.to_string()],
+        });
+        assert_eq!(expr, RuleExpr::Or(
+            matcher("Host", "a"),
+            Box::new(RuleExpr::And(matcher("Host", "b"), Box::new(RuleExpr::Not(matcher("Path", "/"))))),
+        ));
+    }
+
+    #[test]
+    fn syntax_errors() {
+        assert_eq!(parse("Host(`a.com`"), Err(RuleError::UnexpectedEnd));
+        assert_eq!(parse("Host(`a.com)"), Err(RuleError::UnterminatedString(5)));
+        assert_eq!(parse("Host(`a`) & Host(`b`)"), Err(RuleError::UnexpectedChar('&', 10)));
+        assert_eq!(parse("Host(`a`) Host(`b`)"),
+            Err(RuleError::UnexpectedToken("`Host`".to_string(), 10)));
+
+        let result = get_hosts("Host(`a.com`");
+        assert!(result.hosts.is_empty());
+        assert_eq!(result.warnings, vec![RuleWarning::Syntax(RuleError::UnexpectedEnd)]);
+    }
+}
@@ -0,0 +1,59 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context, Result};
+
+// Environment variables that can hold secrets, either directly or within inline JSON
+// (dotenv puts the ones from .env there too). Commands we run don't get to see them.
+pub const ENV_VARS: &[&str] = &["HEADSCALE_AUTH", "TRAEFIK_PASS", "TRAEFIK_NODE_OVERRIDES", "TRAEFIK_ENDPOINTS"];
+
+// Secrets can be given directly, through a file (`*_FILE`, eg. Docker secrets or agenix)
+// or through systemd's credentials (`LoadCredential=`), in that order of preference.
+// The latter two keep them out of /proc/*/environ and ps.
+pub fn resolve(value: Option<&str>, file: Option<&str>, credential: &str, allow_world_readable: bool)
+        -> Result<Option<String>> {
+    if let Some(value) = value {
+        return Ok(Some(value.to_string()));
+    }
+
+    if let Some(file) = file {
+        return read(Path::new(file), allow_world_readable).map(Some);
+    }
+
+    if let Some(dir) = env::var_os("CREDENTIALS_DIRECTORY") {
+        let path = PathBuf::from(dir).join(credential);
+        if path.exists() {
+            return read(&path, allow_world_readable).map(Some);
+        }
+    }
+
+    Ok(None)
+}
+
+// Refuses files holding secrets that anyone on the machine could read
+pub fn check_permissions(path: &Path, allow_world_readable: bool) -> Result<()> {
+    #[cfg(unix)]
+    if !allow_world_readable {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = fs::metadata(path)
+            .with_context(|| format!(r#"Unable to read the secret file "{}""#, path.display()))?
+            .permissions().mode();
+        if mode & 0o004 != 0 {
+            bail!(r#"The secret file "{}" is world-readable, fix its permissions
+or set ALLOW_WORLD_READABLE_SECRETS=true"#, path.display());
+        }
+    }
+
+    Ok(())
+}
+
+pub fn read(path: &Path, allow_world_readable: bool) -> Result<String> {
+    check_permissions(path, allow_world_readable)?;
+
+    let content = fs::read_to_string(path)
+        .with_context(|| format!(r#"Unable to read the secret file "{}""#, path.display()))?;
+
+    // editors and `echo` love to leave a newline at the end
+    Ok(content.trim_end_matches(['\n', '\r']).to_string())
+}
@@ -0,0 +1,394 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::marker::PhantomData;
+use std::path::Path;
+use clap::{Arg, Command, CommandFactory, FromArgMatches, Parser};
+use serde::de::{self, DeserializeSeed, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::Deserialize;
+use anyhow::{Context, Result};
+use crate::headscale::HeadscaleClientDetails;
+use crate::processing::ProcessingSetup;
+use crate::secret;
+use crate::traefik::{TraefikAPIClientDetails, TraefikEndpoint, TraefikNodeOverride};
+
+// Every setting there is, parsed exactly once. Values are layered as
+// defaults < config file < environment (.env included) < command line.
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Settings {
+    #[arg(long = "config", short = 'c', env = "CONFIG",
+        help = r#"TOML or YAML config file, its keys are the long names of the other options
+(eg. `headscale_domain = "https://headscale.example.com"`)"#)]
+    config: Option<String>,
+
+    #[arg(long = "allow_world_readable_secrets", env = "ALLOW_WORLD_READABLE_SECRETS",
+        help = "Allow reading secrets from files that anyone can read", default_value_t = false)]
+    pub allow_world_readable_secrets: bool,
+
+    #[command(flatten)]
+    pub processing: ProcessingSetup,
+
+    #[command(flatten)]
+    pub headscale: HeadscaleClientDetails,
+
+    #[command(flatten)]
+    pub traefik: TraefikAPIClientDetails,
+
+    // Written as tables in the config file, these only apply if the environment
+    // and the command line don't point to a JSON file instead
+    #[arg(skip)]
+    pub traefik_overrides: Option<BTreeMap<String, TraefikNodeOverride>>,
+    #[arg(skip)]
+    pub traefik_endpoints: Option<BTreeMap<String, TraefikEndpoint>>,
+}
+
+// Only used to find the config file before everything else gets parsed
+#[derive(Parser)]
+#[command(ignore_errors = true, disable_help_flag = true, disable_version_flag = true)]
+struct ConfigPath {
+    #[arg(long = "config", short = 'c', env = "CONFIG")]
+    config: Option<String>,
+}
+
+impl Settings {
+    pub fn load() -> Result<Settings> {
+        let path = ConfigPath::parse().config;
+        let file = match &path {
+            Some(path) => FileSettings::read(path)?,
+            None => FileSettings::default(),
+        };
+
+        // The file's values take the place of the defaults, which leaves the environment
+        // and the command line to override them the same way they'd override any default
+        let mut command = Settings::command();
+        for (id, values) in &file.values {
+            command = command.mut_arg(id, |x| x.required(false).default_values(values));
+        }
+
+        let mut matches = command.get_matches();
+        let mut settings = Settings::from_arg_matches_mut(&mut matches).unwrap_or_else(|e| e.exit());
+        settings.processing.logging.init();
+
+        if let Some(path) = path.as_deref().filter(|_| file.has_secrets) {
+            secret::check_permissions(Path::new(path), settings.allow_world_readable_secrets)
+                .context("The config file holds secrets")?;
+        }
+        settings.traefik_overrides = file.traefik_overrides;
+        settings.traefik_endpoints = file.traefik_endpoints;
+
+        settings.headscale.resolve_secrets(settings.allow_world_readable_secrets)?;
+        settings.traefik.resolve_secrets(settings.allow_world_readable_secrets)?;
+
+        Ok(settings)
+    }
+}
+
+// The config file, checked and sorted out, but not yet applied
+#[derive(Default)]
+struct FileSettings {
+    // (argument id, values) for everything that can be set from the command line as well
+    values: Vec<(String, Vec<String>)>,
+    traefik_overrides: Option<BTreeMap<String, TraefikNodeOverride>>,
+    traefik_endpoints: Option<BTreeMap<String, TraefikEndpoint>>,
+    // whether it holds any passwords or keys, which makes the file itself a secret
+    has_secrets: bool,
+}
+
+impl FileSettings {
+    fn read(path: &str) -> Result<FileSettings> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!(r#"Unable to read the config file "{}""#, path))?;
+
+        // both report mistakes along with the line and column they're on
+        if path.ends_with(".yaml") || path.ends_with(".yml") {
+            serde_yaml::from_str(&content)
+                .with_context(|| format!(r#"The config file "{}" is invalid"#, path))
+        } else {
+            toml::from_str(&content)
+                .with_context(|| format!(r#"The config file "{}" is invalid"#, path))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FileValue {
+    Bool(bool),
+    Integer(i64),
+    String(String),
+    List(Vec<FileValue>),
+}
+
+impl FileValue {
+    fn into_string(self) -> String {
+        match self {
+            FileValue::Bool(x)    => x.to_string(),
+            FileValue::Integer(x) => x.to_string(),
+            FileValue::String(x)  => x,
+            FileValue::List(x)    => x.into_iter().map(|x| x.into_string()).collect::<Vec<_>>().join(","),
+        }
+    }
+}
+
+fn find_arg<'a>(command: &'a Command, key: &str) -> Option<&'a Arg> {
+    command.get_arguments().find(|x| x.get_long() == Some(key))
+}
+
+// Checks a value the same way clap would (by parsing it on its own)
+fn check_value(arg: &Arg, value: &str) -> Result<(), String> {
+    // flags are plain booleans here, the same as in the environment
+    if !arg.get_action().takes_values() {
+        return value.parse::<bool>().map(|_| ())
+            .map_err(|_| format!("'{}' is not a boolean", value));
+    }
+
+    let long = arg.get_long().unwrap_or_default();
+    let command = Command::new("config").no_binary_name(true)
+        .arg(arg.clone().env(None).required(false));
+
+    command.try_get_matches_from([format!("--{}={}", long, value)]).map(|_| ()).map_err(|e| {
+        let message = e.to_string();
+        message.lines().next().unwrap_or_default().trim_start_matches("error: ").to_string()
+    })
+}
+
+// The checks happen within the visitors, as that's when the formats know where in the file they are
+
+struct KeySeed<'a>(&'a Command);
+
+impl<'de, 'a> DeserializeSeed<'de> for KeySeed<'a> {
+    type Value = &'a Arg;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_str(self)
+    }
+}
+
+impl<'de, 'a> Visitor<'de> for KeySeed<'a> {
+    type Value = &'a Arg;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the name of a setting")
+    }
+
+    fn visit_str<E: de::Error>(self, key: &str) -> Result<Self::Value, E> {
+        find_arg(self.0, key)
+            .filter(|x| x.get_long() != Some("config"))
+            .ok_or_else(|| E::custom(format!("unknown setting `{}`", key)))
+    }
+}
+
+struct ValueSeed<'a>(&'a Arg);
+
+impl ValueSeed<'_> {
+    fn check<E: de::Error>(&self, values: Vec<String>) -> Result<Vec<String>, E> {
+        check_value(self.0, &values.join(","))
+            .map_err(|e| E::custom(format!("invalid `{}`: {}", self.0.get_long().unwrap_or_default(), e)))?;
+
+        Ok(values)
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for ValueSeed<'_> {
+    type Value = Vec<String>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ValueSeed<'_> {
+    type Value = Vec<String>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a string, a number, a boolean or a list of those")
+    }
+
+    fn visit_bool<E: de::Error>(self, value: bool) -> Result<Self::Value, E> {
+        self.check(vec![value.to_string()])
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Self::Value, E> {
+        self.check(vec![value.to_string()])
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Self::Value, E> {
+        self.check(vec![value.to_string()])
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+        self.check(vec![value.to_string()])
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut values = Vec::new();
+        while let Some(x) = seq.next_element::<FileValue>()? {
+            values.push(x.into_string());
+        }
+
+        if self.0.get_value_delimiter().is_none() {
+            return Err(de::Error::custom(format!("invalid `{}`: a single value is expected, not a list",
+                self.0.get_long().unwrap_or_default())));
+        }
+
+        self.check(values)
+    }
+}
+
+// Either a table, or a string that's handled like any other value (eg. the path to a JSON file)
+enum TableOrValue<T> {
+    Table(T),
+    Value(Vec<String>),
+}
+
+struct TableSeed<'a, T>(&'a Arg, PhantomData<T>);
+
+impl<'de, T: Deserialize<'de>> DeserializeSeed<'de> for TableSeed<'_, T> {
+    type Value = TableOrValue<T>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Visitor<'de> for TableSeed<'_, T> {
+    type Value = TableOrValue<T>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a table or the path to a JSON file")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+        check_value(self.0, value)
+            .map_err(|e| E::custom(format!("invalid `{}`: {}", self.0.get_long().unwrap_or_default(), e)))?;
+
+        Ok(TableOrValue::Value(vec![value.to_string()]))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        T::deserialize(de::value::MapAccessDeserializer::new(map)).map(TableOrValue::Table)
+    }
+}
+
+impl<'de> Deserialize<'de> for FileSettings {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct FileVisitor;
+
+        impl<'de> Visitor<'de> for FileVisitor {
+            type Value = FileSettings;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a table of settings")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let command = Settings::command();
+                let mut settings = FileSettings::default();
+
+                while let Some(arg) = map.next_key_seed(KeySeed(&command))? {
+                    let id = arg.get_id().to_string();
+
+                    let values = match arg.get_long() {
+                        Some("traefik_node_overrides") => match map.next_value_seed(TableSeed(arg, PhantomData))? {
+                            TableOrValue::Table(x) => {
+                                let x: BTreeMap<String, TraefikNodeOverride> = x;
+                                settings.has_secrets |= x.values().any(|x| x.has_password());
+                                settings.traefik_overrides = Some(x);
+                                continue;
+                            },
+                            TableOrValue::Value(x) => x,
+                        },
+                        Some("traefik_endpoints") => match map.next_value_seed(TableSeed(arg, PhantomData))? {
+                            TableOrValue::Table(x) => {
+                                let x: BTreeMap<String, TraefikEndpoint> = x;
+                                settings.has_secrets |= x.values().any(|x| x.has_password());
+                                settings.traefik_endpoints = Some(x);
+                                continue;
+                            },
+                            TableOrValue::Value(x) => x,
+                        },
+                        _ => map.next_value_seed(ValueSeed(arg))?,
+                    };
+
+                    settings.has_secrets |= matches!(arg.get_long(), Some("headscale_auth" | "traefik_pass"));
+                    settings.values.push((id, values));
+                }
+
+                Ok(settings)
+            }
+        }
+
+        deserializer.deserialize_map(FileVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn toml_error(content: &str) -> String {
+        toml::from_str::<FileSettings>(content).err().unwrap().to_string()
+    }
+
+    fn yaml_error(content: &str) -> String {
+        serde_yaml::from_str::<FileSettings>(content).err().unwrap().to_string()
+    }
+
+    #[test]
+    fn values() {
+        let file: FileSettings = toml::from_str(r#"
+headscale_allowed_users = ["server", "ops"]
+traefik_concurrency = 4
+daemon = true
+"#).unwrap();
+
+        assert_eq!(file.values, vec![
+            ("allowed_users".to_string(), vec!["server".to_string(), "ops".to_string()]),
+            ("concurrency".to_string(), vec!["4".to_string()]),
+            ("daemon".to_string(), vec!["true".to_string()]),
+        ]);
+        assert!(!file.has_secrets);
+    }
+
+    #[test]
+    fn tables() {
+        let file: FileSettings = serde_yaml::from_str(r#"
+traefik_node_overrides:
+  nas:
+    port: 8443
+traefik_endpoints:
+  lanbox:
+    url: http://192.168.1.5:8080
+    addresses: [192.168.1.5]
+    password: hunter2
+"#).unwrap();
+
+        assert!(file.values.is_empty());
+        assert!(file.traefik_overrides.unwrap().contains_key("nas"));
+        assert!(file.traefik_endpoints.unwrap().contains_key("lanbox"));
+        assert!(file.has_secrets);
+
+        // a path to a JSON file instead is like any other value
+        let file: FileSettings = toml::from_str(r#"traefik_node_overrides = "/etc/overrides.json""#).unwrap();
+        assert_eq!(file.values, vec![("traefik_overrides_path".to_string(), vec!["/etc/overrides.json".to_string()])]);
+        assert!(file.traefik_overrides.is_none());
+    }
+
+    #[test]
+    fn error_locations() {
+        let content = "daemon = true\n\ntraefik_concurrency = 0\n";
+        assert!(toml_error(content).contains("line 3, column 23"));
+        assert!(toml_error(content).contains("invalid `traefik_concurrency`"));
+
+        assert!(toml_error("daemon = true\nheadscale_domian = \"x\"\n").contains("line 2, column 1"));
+        assert!(toml_error("[traefik_node_overrides.nas]\nport = 8443\nprot = 1\n").contains("line 3, column 1"));
+        assert!(toml_error("interval = [1, 2]\n").contains("a single value is expected"));
+        assert!(toml_error("config = \"other.toml\"\n").contains("unknown setting `config`"));
+
+        assert!(yaml_error("daemon: true\n\ntraefik_concurrency: 0\n").contains("line 3 column 22"));
+        assert!(yaml_error("daemon: maybe\n").contains("'maybe' is not a boolean at line 1 column 9"));
+        assert!(yaml_error("daemon: true\nheadscale_domian: x\n").contains("unknown setting `headscale_domian` at line 2 column 1"));
+        assert!(yaml_error("traefik_endpoints:\n  lanbox:\n    url: x\n    adresses: []\n").contains("line 4"));
+    }
+}
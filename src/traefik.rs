@@ -1,31 +1,32 @@
+use std::collections::BTreeMap;
+use std::path::Path;
 use std::string::ToString;
+use std::time::Duration;
 use base64::Engine;
-use clap::Parser;
+use clap::Args;
 use base64::prelude::BASE64_STANDARD;
 use reqwest::{header, Url};
 use serde::Deserialize;
 use serde_json::from_str;
 use thiserror::Error;
-use regex::Regex;
+use log::debug;
+use crate::{logging, secret};
+use crate::rule::{self, RuleHosts};
 
 #[derive(Debug, Error)]
+#[allow(clippy::enum_variant_names)]
 enum TraefikUserError {
-    #[error(r#"No valid Traefik host found.\
-Either no eligible Headscale servers exist or no Traefik hosts was defined."#)]
-    NoHosts,
     #[error(r#"No valid Traefik URL prefix (eg. "https://") has been specified"#)]
     NoPrefix,
     #[error(r#"No valid Traefik URL suffix (eg. "/trafik") has been specified"#)]
     NoSuffix,
+    #[error("No Traefik password has been specified (TRAEFIK_PASS or TRAEFIK_PASS_FILE)")]
+    NoPassword,
 }
 
 // Don't derive Debug as it can leak sensitive info the syslog
-#[derive(Parser, Clone, Debug)]
+#[derive(Args, Clone)]
 pub struct TraefikAPIClientDetails {
-    // In case of a double match in a query, preferred servers are picked first
-    #[arg(long = "traefik_domain", alias = "td", env = "TRAEFIK_DOMAIN",
-        help = "Domain of a single Traefik server (more can be added via Tailscale)")]
-    host: Option<String>,
     #[arg(long = "traefik_domain_prefix", alias = "tdp", env = "TRAEFIK_DOMAIN_PREFIX",
         help = r#"Prefixes appended to generated Traefik server names \
 (these names are obtained from your headscale server)"#)]
@@ -39,40 +40,138 @@ pub struct TraefikAPIClientDetails {
     user: String,
     #[arg(long = "traefik_pass", alias = "tp", env = "TRAEFIK_PASS",
         help = "Traefik basic authentication password (shared among all hosts)")]
-    password: String,
+    password: Option<String>,
+    #[arg(long = "traefik_pass_file", alias = "tpf", env = "TRAEFIK_PASS_FILE",
+        help = r#"File containing the Traefik password (used if TRAEFIK_PASS isn't set,
+falls back to the "traefik_pass" systemd credential)"#)]
+    password_file: Option<String>,
+    #[arg(long = "traefik_connect_timeout", alias = "tct", env = "TRAEFIK_CONNECT_TIMEOUT",
+        help = "Seconds to wait for a connection to a Traefik host", default_value_t = 5)]
+    connect_timeout: u64,
+    #[arg(long = "traefik_timeout", alias = "tt", env = "TRAEFIK_TIMEOUT",
+        help = "Seconds to wait for a Traefik host to fully respond (connecting included)",
+        default_value_t = 15)]
+    timeout: u64,
+
+    // Filled in per node, either the node's address or the full URL of an endpoint
+    #[arg(skip)]
+    host: Option<String>,
+    #[arg(skip)]
+    url: Option<String>,
+
+    // Per-node overrides, these can't be set from the command line
+    #[arg(skip)]
+    scheme: Option<String>,
+    #[arg(skip)]
+    port: Option<u16>,
+    #[arg(skip)]
+    path: Option<String>,
+}
+
+// Connection details that differ for a single node (or all nodes with a certain tag),
+// anything left out falls back to the global TRAEFIK_* settings.
+// Don't derive Debug as it can leak sensitive info the syslog
+#[derive(Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct TraefikNodeOverride {
+    scheme:        Option<String>,
+    port:          Option<u16>,
+    path:          Option<String>,
+    user:          Option<String>,
+    password:      Option<String>,
+    password_file: Option<String>,
+}
+
+// A Traefik instance that isn't a Headscale node (eg. on a subnet-routed LAN),
+// polled along with the discovered ones. Its domains point to `addresses`.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TraefikEndpoint {
+    // eg. "http://192.168.1.5:8080", the API paths get appended to it
+    pub url:       String,
+    pub addresses: Vec<String>,
+    user:          Option<String>,
+    password:      Option<String>,
+    password_file: Option<String>,
+}
+
+// The password given directly wins over the one in the file, same as for TRAEFIK_PASS
+fn resolve_password(password: &mut Option<String>, file: Option<&str>, allow_world_readable: bool)
+        -> anyhow::Result<()> {
+    if let (None, Some(file)) = (&password, file) {
+        *password = Some(secret::read(Path::new(file), allow_world_readable)?);
+    }
+
+    Ok(())
+}
+
+impl TraefikNodeOverride {
+    pub fn has_password(&self) -> bool {
+        self.password.is_some()
+    }
+
+    pub fn resolve_secrets(&mut self, allow_world_readable: bool) -> anyhow::Result<()> {
+        resolve_password(&mut self.password, self.password_file.as_deref(), allow_world_readable)
+    }
+}
+
+impl TraefikEndpoint {
+    pub fn has_password(&self) -> bool {
+        self.password.is_some()
+    }
+
+    pub fn resolve_secrets(&mut self, allow_world_readable: bool) -> anyhow::Result<()> {
+        resolve_password(&mut self.password, self.password_file.as_deref(), allow_world_readable)
+    }
 }
 
 impl TraefikAPIClientDetails {
-    fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
-        if !self.prefix.is_some() {
+    // Checks everything that is shared among all hosts, the host itself is filled in later
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.prefix.is_none() {
             return Err(Box::new(TraefikUserError::NoPrefix))
         }
 
-        if !self.suffix.is_some() {
+        if self.suffix.is_none() {
             return Err(Box::new(TraefikUserError::NoSuffix))
         }
 
-        if !self.host.is_some() {
-            return Err(Box::new(TraefikUserError::NoHosts))
+        if self.password.is_none() {
+            return Err(Box::new(TraefikUserError::NoPassword))
         }
 
         Ok(())
     }
-    pub fn from_custom_host(host: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let mut details = TraefikAPIClientDetails::parse();
-        details.host = Some(host.to_string());
 
-        details.validate()?;
+    pub fn resolve_secrets(&mut self, allow_world_readable: bool) -> anyhow::Result<()> {
+        self.password = secret::resolve(self.password.as_deref(), self.password_file.as_deref(),
+            "traefik_pass", allow_world_readable)?;
+
+        Ok(())
+    }
+
+    pub fn with_host(&self, host: &str) -> Self {
+        let mut details = self.clone();
+        details.host = Some(host.to_string());
 
-        Ok(details)
+        details
     }
 
-    fn new() -> Result<Self, Box<dyn std::error::Error>> {
-        let details = TraefikAPIClientDetails::parse();
+    pub fn with_endpoint(&self, endpoint: &TraefikEndpoint) -> Self {
+        let mut details = self.clone();
+        details.url = Some(endpoint.url.trim_end_matches('/').to_string());
+        if let Some(x) = &endpoint.user     { details.user = x.clone(); }
+        if let Some(x) = &endpoint.password { details.password = Some(x.clone()); }
 
-        details.validate()?;
+        details
+    }
 
-        Ok(TraefikAPIClientDetails::parse())
+    pub fn apply_override(&mut self, node_override: &TraefikNodeOverride) {
+        if let Some(x) = &node_override.scheme   { self.scheme = Some(x.clone()); }
+        if let Some(x) = node_override.port      { self.port = Some(x); }
+        if let Some(x) = &node_override.path     { self.path = Some(x.clone()); }
+        if let Some(x) = &node_override.user     { self.user = x.clone(); }
+        if let Some(x) = &node_override.password { self.password = Some(x.clone()); }
     }
 }
 
@@ -82,41 +181,106 @@ pub struct TraefikAPIClient {
     client: reqwest::blocking::Client,
 }
 
+// Traefik keeps HTTP and TCP routers on separate endpoints
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum TraefikProtocol {
+    #[default]
+    Http,
+    Tcp,
+}
+
+impl TraefikProtocol {
+    fn api_path(&self) -> &'static str {
+        match self {
+            TraefikProtocol::Http => "/api/http/routers",
+            TraefikProtocol::Tcp  => "/api/tcp/routers",
+        }
+    }
+
+    // TCP services don't report the health of their servers
+    fn services_api_path(&self) -> Option<&'static str> {
+        match self {
+            TraefikProtocol::Http => Some("/api/http/services"),
+            TraefikProtocol::Tcp  => None,
+        }
+    }
+}
+
 // This API response is much fatter, but I don't need most of it
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct TraefikRouter {
-    //entry_points: Vec<String>,
+    // eg. `whoami@docker`
+    pub name:     String,
+    // eg. `docker`, `file` or `internal`
+    #[serde(default)]
+    pub provider: String,
+    #[serde(default)]
+    pub entry_points: Vec<String>,
     service:      String,
-    rule:         String,
-    //status:       String,
+    pub rule:     String,
+    // enabled, disabled or warning, the latter two come with a list of errors
+    #[serde(default)]
+    pub status:   String,
+    #[serde(default)]
+    pub error:    Vec<String>,
+
+    // Only its presence matters to us, the contents differ between HTTP and TCP
+    pub tls: Option<serde_json::Value>,
 
     // We use this field to determine if a certain middleware needs to be present
     // for the logic to decide whether to include it in the final DNS output
     pub middlewares: Option<Vec<String>>,
+
+    // Not part of the response, filled in depending on which endpoint we've asked
+    #[serde(skip)]
+    pub protocol: TraefikProtocol,
+
+    // Not part of the response either, whether the service behind it has any servers UP
+    // (None if it wasn't checked or Traefik doesn't know)
+    #[serde(skip)]
+    pub service_up: Option<bool>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct TraefikService {
+    name: String,
+    // Only load balancers have these, keyed by the server's URL (eg. "UP" or "DOWN")
+    server_status: Option<BTreeMap<String, String>>,
 }
 
 // Implement PartialEq manually
 impl PartialEq for TraefikRouter {
     fn eq(&self, other: &Self) -> bool {
-        // Compare only the desired fields
-        self.service == other.service && self.rule == other.rule
+        // Compare only the desired fields, the health ones too so that a broken router
+        // on one node doesn't hide a working copy of it on another
+        self.protocol == other.protocol && self.service == other.service && self.rule == other.rule
+            && self.status == other.status && self.service_up == other.service_up
     }
 }
 
 impl TraefikRouter {
-    pub fn get_domain_list(&self) -> Vec<String> {
-        // Regular expression to match domain names in Traefik rules
-        let re = Regex::new(r#"(Host)\(`([a-z0-9,.]+)`\)"#).unwrap();
-        let mut domains = Vec::new();
-
-        for cap in re.captures_iter(self.rule.as_str()) {
-            if let Some(domain) = cap.get(2) {
-                domains.push(domain.as_str().to_string());
-            }
+    pub fn has_tls(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        // older versions of Traefik might not report it at all
+        self.status.is_empty() || self.status == "enabled"
+    }
+
+    // Routers refer to services of their own provider without the @provider part
+    fn service_name(&self) -> String {
+        match self.service.contains('@') || self.provider.is_empty() {
+            true  => self.service.clone(),
+            false => format!("{}@{}", self.service, self.provider),
         }
+    }
 
-        domains
+    // Every domain this router can possibly serve, as well as the ones we couldn't figure out
+    pub fn get_domain_list(&self) -> RuleHosts {
+        rule::get_hosts(self.rule.as_str())
     }
 }
 
@@ -124,21 +288,42 @@ impl TraefikAPIClient {
     pub fn from(details: &TraefikAPIClientDetails) -> Result<Self, Box<dyn std::error::Error>> {
         let mut headers = header::HeaderMap::new();
 
-        let authorization: String = String::from(&details.user) + ":" + &details.password;
+        let password = details.password.as_deref().unwrap_or_default();
+        let authorization: String = String::from(&details.user) + ":" + password;
         let authorization = BASE64_STANDARD.encode(authorization);
 
+        logging::add_secret(password);
+        logging::add_secret(&authorization);
+
         let mut auth_value = header::HeaderValue::from_str(&format!("Basic {}", authorization))?;
         auth_value.set_sensitive(true);
         headers.insert(header::AUTHORIZATION, auth_value);
 
         let client = reqwest::blocking::Client::builder()
             .default_headers(headers)
+            .connect_timeout(Duration::from_secs(details.connect_timeout))
+            .timeout(Duration::from_secs(details.timeout))
             .build()?;
 
-        let url: String = String::from(&details.prefix.clone().unwrap()) +
-            &details.host.clone().unwrap() + &details.suffix.clone().unwrap();
+        let url: String = match &details.url {
+            Some(url) => url.clone(),
+            None => details.prefix.clone().unwrap_or_default() +
+                details.host.as_deref().unwrap_or_default() + details.suffix.as_deref().unwrap_or_default(),
+        };
 
-        let base_url = Url::parse(url.as_str())?;
+        let mut base_url = Url::parse(url.as_str())?;
+
+        if let Some(scheme) = &details.scheme {
+            base_url.set_scheme(scheme)
+                .map_err(|_| format!(r#"Unable to use the "{}" scheme for Traefik"#, scheme))?;
+        }
+        if let Some(port) = details.port {
+            base_url.set_port(Some(port))
+                .map_err(|_| format!("Unable to use port {} for Traefik", port))?;
+        }
+        if let Some(path) = &details.path {
+            base_url.set_path(path);
+        }
 
         Ok(TraefikAPIClient {
             base_url,
@@ -146,31 +331,42 @@ impl TraefikAPIClient {
         })
     }
 
-    #[allow(dead_code)]
-    pub fn validate(client: &Self) -> Result<(), Box<dyn std::error::Error>> {
-        let url = Url::parse(&(client.base_url.to_string() + "/api/overview"))?;
+    pub fn get_router_list(client: &Self, protocol: TraefikProtocol)
+            -> Result<Vec<TraefikRouter>, Box<dyn std::error::Error>> {
+        let urls = Url::parse(&(client.base_url.to_string() + protocol.api_path()))?;
+        debug!(url = urls.as_str(); "Querying Traefik routers");
 
-        let _res = client.client.get(url).send()?.error_for_status()?;
+        let res = client.client.get(urls.clone()).send()?.error_for_status()?;
+        let mut routers = from_str::<Vec<TraefikRouter>>(&res.text()?)?;
 
-        Ok(())
-    }
+        debug!(url = urls.as_str(), routers = routers.len(); "Received Traefik routers");
 
-    #[allow(dead_code)]
-    pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
-        let details = TraefikAPIClientDetails::new()?;
+        for router in &mut routers {
+            router.protocol = protocol;
+        }
 
-        let client = TraefikAPIClient::from(&details)?;
+        Ok(routers)
+    }
 
-        Self::validate(&client)?;
+    // Fills in whether the services behind the given routers have any servers that are UP
+    pub fn check_services(client: &Self, protocol: TraefikProtocol, routers: &mut [TraefikRouter])
+            -> Result<(), Box<dyn std::error::Error>> {
+        let Some(path) = protocol.services_api_path() else { return Ok(()) };
 
-        Ok(client)
-    }
+        let urls = Url::parse(&(client.base_url.to_string() + path))?;
+        debug!(url = urls.as_str(); "Querying Traefik services");
 
-    pub fn get_router_list(client: &Self) -> Result<Vec<TraefikRouter>, Box<dyn std::error::Error>> {
-        let urls = Url::parse(&(client.base_url.to_string() + "/api/http/routers"))?;
-        let res = client.client.get(urls).send()?.error_for_status()?;
-        let routers = from_str::<Vec<TraefikRouter>>(&res.text()?)?;
+        let res = client.client.get(urls.clone()).send()?.error_for_status()?;
+        let services = from_str::<Vec<TraefikService>>(&res.text()?)?;
 
-        Ok(routers)
+        for router in routers.iter_mut().filter(|x| x.protocol == protocol) {
+            let name = router.service_name();
+            router.service_up = services.iter()
+                .find(|x| x.name == name)
+                .and_then(|x| x.server_status.as_ref())
+                .map(|x| x.values().any(|x| x == "UP"));
+        }
+
+        Ok(())
     }
 }